@@ -1,19 +1,34 @@
+mod assertion;
+mod common;
+mod conditional;
 mod directive;
 pub(super) mod emit;
+mod include;
+mod local_label;
+mod macros;
+mod message;
+mod metadata;
+mod repeat;
+mod structure;
 pub mod symbol_table;
 use itertools::izip;
 
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::iter::Peekable;
+use std::path::PathBuf;
+use std::rc::Rc;
 
 use anyhow::{anyhow, bail};
 use spdlog::debug;
 
 use crate::assembler::symbol_table::{SymbolTable, Type};
-use crate::expression::{BinaryOp, Node, parse_expr};
+use crate::diagnostic::{self, Span, SpanContext, error_at, error_span};
+use crate::expression::{Argument, BinaryOp, Builtin, Node, parse_spanned_expr};
 use crate::instruction::Mnemonic;
 use crate::opcode::{InstEncoding, MAX_OPERANDS, OperandFlags, Relocation, get_encodings};
-use crate::section::SectionMap;
-use crate::{operand, section, tokens};
+use crate::section::{Section, SectionMap};
+use crate::{module, operand, section, tokens};
 pub use emit::calculate_disp32_offset;
 
 use super::lexer::*;
@@ -21,10 +36,54 @@ use super::tokens::*;
 
 use anyhow::{Context, Result};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AssemblerToken {
     pub token: Token,
-    pub line: usize,
+    pub span: Span,
+}
+
+/// The tokens of a single line including its trailing newline, if it has one
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub tokens: Vec<AssemblerToken>,
+    /// The macro expansion this line was produced by
+    pub expansion: Option<Rc<macros::Expansion>>,
+}
+
+impl Line {
+    /// Splits `tokens` into lines that aren't part of any macro expansion
+    fn split(tokens: Vec<AssemblerToken>) -> Vec<Line> {
+        let mut lines = Vec::new();
+        let mut current = Vec::new();
+
+        for token in tokens {
+            let is_newline = matches!(token.token, Token::Newline);
+            current.push(token);
+            if is_newline {
+                lines.push(Line {
+                    tokens: std::mem::take(&mut current),
+                    expansion: None,
+                });
+            }
+        }
+
+        if !current.is_empty() {
+            lines.push(Line {
+                tokens: current,
+                expansion: None,
+            });
+        }
+
+        lines
+    }
+
+    /// The span of the first token in this line
+    fn span(&self) -> Span {
+        self.tokens
+            .first()
+            .map(|token| token.span)
+            .unwrap_or_default()
+    }
 }
 
 pub trait AsmTokenIter<'a>: Iterator<Item = &'a AssemblerToken> {
@@ -176,6 +235,8 @@ struct Instruction {
     indexes: [MemoryIndex; MAX_OPERANDS],
     /// Whether a relocation has been requested
     reloc: [bool; MAX_OPERANDS],
+    /// Where in the source code each operand was parsed from
+    spans: [Span; MAX_OPERANDS],
     // /// Reloaction per operand
     // reloc: [Relocation; MAX_OPERANDS],
 }
@@ -187,8 +248,8 @@ pub struct ForwardReferenceEntry {
     pub offset: usize,
     pub expr: Box<Node>,
 
-    /// The line number the relocation was emitted on
-    pub line_number: usize,
+    /// Where the expression is in the source code
+    pub span: Span,
 }
 
 impl ForwardReferenceEntry {
@@ -197,16 +258,34 @@ impl ForwardReferenceEntry {
         section: usize,
         offset: usize,
         expr: Box<Node>,
-        line_number: usize,
+        span: Span,
     ) -> Self {
         Self {
             relocation,
             section,
             offset,
             expr,
-            line_number,
+            span,
         }
     }
+
+    /// Replaces the variables in the expression with their current values, since they can be set
+    /// to something else before the expression is resolved
+    pub fn freeze_variables(mut self, symbols: &SymbolTable) -> Self {
+        self.expr
+            .substitute(&|id| symbols.get_variable(id).map(Node::Constant));
+        self
+    }
+}
+
+/// A `.equ` whose value wasn't known when it was defined. It's resolved once the module is built
+#[derive(Debug)]
+pub struct Equ {
+    pub name: String,
+    pub expr: Box<Node>,
+    /// The section the `.equ` was in, used for builtins like `sizeof`
+    pub section: usize,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -240,19 +319,72 @@ impl ExprResult {
 #[derive(Debug)]
 pub struct Assembler {
     pub filename: String,
+    /// The source code being assembled. Kept around to show the offending line in diagnostics
+    pub source: String,
+    /// Directories searched for `.include` and `.incbin` files after the directory of the file
+    /// doing the including
+    include_paths: Vec<PathBuf>,
+    /// Every file included so far. The file with the id `n` in `Span::file` is at index `n - 1`
+    included: Vec<include::IncludedFile>,
     pub symbols: SymbolTable,
     pub global_symbols: Vec<String>,
+    /// Symbols declared with `.extern`, which are defined in another file
+    pub extern_symbols: Vec<String>,
+    /// Symbols declared with `.weak`. They are global if they're defined and resolve to 0 if
+    /// nothing defines them
+    pub weak_symbols: Vec<String>,
+    /// Types set with `.type`, applied once the module is built since the symbol can be defined
+    /// after the directive
+    pub symbol_types: Vec<(String, Type, Span)>,
+    /// Sizes set with `.size`, applied the same way as `symbol_types`
+    pub symbol_sizes: Vec<(String, u64, Span)>,
+    /// Symbols declared with `.comm`, which the linker allocates
+    pub commons: Vec<(module::Common, Span)>,
+    /// Whether undefined symbols have to be declared with `.extern`. Checked once the module is
+    /// built since that's when every label is known
+    pub strict: bool,
+    /// Warnings of the line being parsed, which are shown once it's done
+    warnings: Vec<anyhow::Error>,
+    /// How many warnings were shown
+    pub warning_count: usize,
+    /// Whether any warning makes building the module fail
+    pub warnings_as_errors: bool,
 
     pub forward_references: Vec<ForwardReferenceEntry>,
+    /// `.equ`s that refer to symbols that weren't defined yet, in the order they were defined
+    pub equs: Vec<Equ>,
+    /// `.assert`s that depend on labels, which are checked once the module is built or linked
+    pub assertions: Vec<assertion::Assertion>,
 
     pub sections: SectionMap,
 
+    /// The lines that still have to be parsed, in reverse order so the next line is at the end.
+    /// Macro expansions push their lines on top
+    lines: Vec<Line>,
+    macros: HashMap<String, Rc<macros::Macro>>,
+    /// The number of macro expansions and repetition iterations so far, used as the value of `\@`
+    macro_count: usize,
+    /// The `.if` blocks the current line is in, innermost last
+    conditionals: Vec<conditional::Conditional>,
+    /// How many times each numeric local label has been defined so far
+    local_labels: HashMap<u64, usize>,
+    local_label_references: Vec<local_label::LocalLabelReference>,
+    /// The last label without a dot in its name, which labels starting with a dot belong to
+    label_scope: Option<String>,
+    structs: HashMap<String, structure::Struct>,
+    /// The `.struct` whose fields are being declared
+    open_struct: Option<structure::OpenStruct>,
+    /// The `.func` whose body is being assembled
+    open_function: Option<metadata::OpenFunction>,
+    /// The name and location of every `.pushsection` that hasn't been popped yet, innermost last
+    pushed_sections: Vec<(String, Span)>,
+
     /// The current line number being parsed
     current_line: usize,
 }
 
 impl Assembler {
-    const NO_SECTION: usize = usize::MAX;
+    pub(crate) const NO_SECTION: usize = usize::MAX;
 }
 
 impl Assembler {
@@ -282,16 +414,17 @@ impl Assembler {
                     return Ok(ExprResult::new_reloc());
                 };
 
-                // The symbol is a label
+                // The symbol is a label. A weak one always gets a relocation since a global
+                // definition in another module can override it
                 if let Some(section) = symbol.section_index {
-                    if section == current_section {
+                    if section == current_section && !self.weak_symbols.contains(id) {
                         Ok(ExprResult::Constant {
                             constant: symbol.value,
                             section: Some(section),
                             relocation: false,
                         })
                     }
-                    // The label exists in a different section
+                    // The label exists in a different section or is weak
                     else {
                         Ok(ExprResult::Constant {
                             constant: 0,
@@ -304,6 +437,11 @@ impl Assembler {
                 }
             }
             Node::Expression(expr) => self.evaluate_expression(expr, current_section),
+            Node::Call { function, args } => Ok(ExprResult::new_imm(self.evaluate_call(
+                *function,
+                args,
+                current_section,
+            )?)),
             Node::BinaryOp { op, left, right } => {
                 let left = self.evaluate_expression(left, current_section)?;
                 let right = self.evaluate_expression(right, current_section)?;
@@ -326,10 +464,21 @@ impl Assembler {
                     bail!("Invalid operation on a register")
                 };
 
-                if *op == BinaryOp::Div && rhs_constant == 0 {
+                // A divisor that isn't known yet is checked once it is resolved
+                if matches!(op, BinaryOp::Div | BinaryOp::Mod)
+                    && rhs_constant == 0
+                    && !rhs_relocation
+                {
                     bail!("Division by zero");
                 }
 
+                // Labels only have a known value relative to their section, so the only
+                // meaningful operations on them are offsetting and taking differences. Undefined
+                // symbols might still be constants and are checked once they are resolved
+                if !op.is_relocatable() && (lhs_section.is_some() || rhs_section.is_some()) {
+                    bail!("Only + and - can be used on labels");
+                }
+
                 let result = op.calculate(lhs_constant, rhs_constant);
 
                 let section = match (lhs_section, rhs_section) {
@@ -339,6 +488,9 @@ impl Assembler {
                     // If you are subtracting two labels (from the same section) then the result
                     // can be represented as an absolute value
                     (Some(_), Some(_)) if *op == BinaryOp::Sub => None,
+                    // Subtracting a label from a symbol that isn't defined yet is most likely
+                    // the difference of two labels, which is resolved once the module is built
+                    (None, Some(_)) if *op == BinaryOp::Sub && lhs_relocation => None,
                     // `lhs` and `rhs` are equal
                     (Some(lhs), Some(_rhs)) => Some(lhs),
                     (Some(lhs), None) => Some(lhs),
@@ -381,6 +533,78 @@ impl Assembler {
         }
     }
 
+    /// Evaluates a call to a builtin function. The result is always an absolute value, so every
+    /// argument has to be known at this point
+    pub fn evaluate_call(
+        &self,
+        function: Builtin,
+        args: &[Argument],
+        current_section: usize,
+    ) -> Result<u64> {
+        let constant = |index: usize| -> Result<u64> {
+            let Some(Argument::Expr(expr)) = args.get(index) else {
+                bail!("{function} expects an expression as argument {}", index + 1);
+            };
+
+            match self.evaluate_expression(expr, current_section)? {
+                ExprResult::Constant {
+                    constant,
+                    section: None,
+                    relocation: false,
+                } => Ok(constant),
+                ExprResult::Register(_) => bail!("Cannot pass a register to {function}"),
+                ExprResult::Constant { .. } => {
+                    bail!(
+                        "Cannot pass a relocatable value to {function}, its value isn't known yet"
+                    )
+                }
+            }
+        };
+        let name = |index: usize| -> Result<&str> {
+            match args.get(index) {
+                Some(Argument::Name(name)) => Ok(name),
+                _ => bail!("{function} expects a name as argument {}", index + 1),
+            }
+        };
+        let section = |index: usize| -> Result<&Section> {
+            let name = name(index)?;
+            self.sections
+                .get(name)
+                .map(|(_, section)| section)
+                .with_context(|| format!("Section {name} has not been defined"))
+        };
+
+        let value = match function {
+            Builtin::Defined => {
+                let name = name(0)?;
+                (name != "." && self.symbols.get_symbol(name).is_some()) as u64
+            }
+            Builtin::Sizeof => section(0)?.size() as u64,
+            Builtin::Alignof => section(0)?.alignment,
+            Builtin::AlignUp => {
+                let (value, align) = (constant(0)?, constant(1)?);
+                if align == 0 {
+                    bail!("Cannot align to 0");
+                }
+                value.div_ceil(align).wrapping_mul(align)
+            }
+            Builtin::Lo32 => constant(0)? & 0xFFFF_FFFF,
+            Builtin::Hi32 => constant(0)? >> 32,
+            Builtin::Min => (constant(0)? as i64).min(constant(1)? as i64) as u64,
+            Builtin::Max => (constant(0)? as i64).max(constant(1)? as i64) as u64,
+            Builtin::Log2 => match constant(0)?.checked_ilog2() {
+                Some(log) => log.into(),
+                None => bail!("Cannot take the logarithm of 0"),
+            },
+            Builtin::Strlen => match args.first() {
+                Some(Argument::String(string)) => string.len() as u64,
+                _ => bail!("{function} expects a string"),
+            },
+        };
+
+        Ok(value)
+    }
+
     /// Returns a tuple of the result of the expression, and whether a relocation needs to be
     /// emitted
     fn evaluate_non_operand_expression(&self, expr: &Box<Node>) -> Result<(u64, bool)> {
@@ -449,6 +673,16 @@ impl Assembler {
                 }
 
                 if left_result.is_number() && right_result.is_number() {
+                    if !op.is_relocatable() && (left_result.is_label || right_result.is_label) {
+                        return Err(anyhow!("Only + and - can be used on labels"));
+                    }
+                    if matches!(op, BinaryOp::Div | BinaryOp::Mod)
+                        && right_result.disp == 0
+                        && !right_relocation
+                    {
+                        return Err(anyhow!("Division by zero"));
+                    }
+
                     let new_value = op.calculate(left_result.disp, right_result.disp);
 
                     let mut index = MemoryIndex::disp(new_value);
@@ -589,6 +823,10 @@ impl Assembler {
                 (result, relocation)
             }
             Node::Expression(expr) => self.evaluate_memory_index(expr, current_section)?,
+            Node::Call { function, args } => (
+                MemoryIndex::disp(self.evaluate_call(*function, args, current_section)?),
+                false,
+            ),
         };
 
         Ok((index, relocation))
@@ -597,86 +835,281 @@ impl Assembler {
 
 impl Assembler {
     pub fn assemble(filename: String, source: String) -> Result<Self> {
+        Self::assemble_with_include_paths(filename, source, Vec::new())
+    }
+
+    /// Same as `assemble` but `.include` and `.incbin` also search `include_paths`
+    pub fn assemble_with_include_paths(
+        filename: String,
+        source: String,
+        include_paths: Vec<PathBuf>,
+    ) -> Result<Self> {
         debug!("Assembling file {filename}");
+
+        let tokens = Self::tokenize(&source);
+
         let mut assembler = Assembler {
             filename,
+            source,
+            include_paths,
+            included: Vec::new(),
             symbols: SymbolTable::new(),
             global_symbols: Vec::new(),
+            extern_symbols: Vec::new(),
+            weak_symbols: Vec::new(),
+            symbol_types: Vec::new(),
+            symbol_sizes: Vec::new(),
+            commons: Vec::new(),
+            strict: false,
+            warnings: Vec::new(),
+            warning_count: 0,
+            warnings_as_errors: false,
             forward_references: Vec::new(),
+            equs: Vec::new(),
+            assertions: Vec::new(),
             sections: SectionMap::new(),
+            lines: Vec::new(),
+            macros: HashMap::new(),
+            macro_count: 0,
+            conditionals: Vec::new(),
+            local_labels: HashMap::new(),
+            local_label_references: Vec::new(),
+            label_scope: None,
+            structs: HashMap::new(),
+            open_struct: None,
+            open_function: None,
+            pushed_sections: Vec::new(),
             current_line: 0,
         };
 
-        let lexer = Lexer::new(&source);
-        let iter = TokenIter::new(lexer);
-
-        let mut current_line = 1usize;
-        let tokens: Vec<AssemblerToken> = match iter
-            .map(|token| match token {
-                Ok(token) => {
-                    if let Token::Newline = token {
-                        current_line += 1;
-                    }
-
-                    Ok(AssemblerToken {
-                        token,
-                        line: current_line,
-                    })
-                }
-                Err(e) => Err(e),
-            })
-            .collect::<Result<_>>()
-        {
-            Ok(vec) => vec,
-            Err(e) => bail!("Error {}:{current_line}\n\t{e}", assembler.filename),
+        let tokens = match tokens {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                let span = error_span(&e).expect("Tokenize errors always have a span");
+                bail!("{}", assembler.format_error(&e, span, None))
+            }
         };
 
-        let mut token_iter = tokens.iter().peekable();
-
-        let result = assembler.parse_source(&mut token_iter);
+        let result = assembler.parse_source(tokens);
 
         result
             .then(|| assembler)
             .with_context(|| "Failed to assemble source")
     }
 
-    fn parse_source<'a>(&mut self, token_iter: &mut Peekable<impl AsmTokenIter<'a>>) -> bool {
-        let mut success = true;
+    /// Tokenizes `source`. An error points at the token that couldn't be parsed
+    pub fn tokenize(source: &str) -> Result<Vec<AssemblerToken>> {
+        Self::collect_tokens(Lexer::new(source))
+    }
+
+    /// Tokenizes `source` with spans pointing into `file`
+    fn tokenize_file(source: &str, file: usize) -> Result<Vec<AssemblerToken>> {
+        Self::collect_tokens(Lexer::for_file(source, file))
+    }
 
-        while let Some(token) = token_iter.next() {
-            self.current_line = token.line;
-            if let Err(e) = self.parse_token(&token, token_iter) {
-                println!("Error {}:{}:\n\t{e}", self.filename, token.line);
+    fn collect_tokens(lexer: Lexer) -> Result<Vec<AssemblerToken>> {
+        TokenIter::new(lexer)
+            .map(|token| token.map(|(token, span)| AssemblerToken { token, span }))
+            .collect()
+    }
+
+    fn parse_source(&mut self, tokens: Vec<AssemblerToken>) -> bool {
+        let mut success = true;
+        // Every iteration of a repetition parses the same lines again, their diagnostics are only
+        // shown the first time
+        let mut reported = HashSet::new();
+
+        self.lines = Line::split(tokens);
+        self.lines.reverse();
+
+        while let Some(line) = self.lines.pop() {
+            if let Err(e) = self.parse_line(&line) {
+                let error = self.format_error(&e, line.span(), line.expansion.as_deref());
+                if reported.insert(error.clone()) {
+                    println!("{error}");
+                }
                 success = false;
-                while let Some(token) = token_iter.next() {
-                    if let Token::Newline = token.token {
-                        break;
-                    }
+            }
+
+            for warning in std::mem::take(&mut self.warnings) {
+                let warning = self.format_warning(&warning, line.span(), line.expansion.as_deref());
+                if reported.insert(warning.clone()) {
+                    println!("{warning}");
+                    self.warning_count += 1;
                 }
             }
         }
 
+        for e in self.check_end_of_source() {
+            println!("{}", self.format_error(&e, Span::default(), None));
+            success = false;
+        }
+
         success
     }
 
+    /// Returns the errors that can only be found once every line has been parsed, like blocks
+    /// that were never closed
+    fn check_end_of_source(&mut self) -> Vec<anyhow::Error> {
+        let mut errors = self.check_local_label_references();
+        errors.extend(self.check_unterminated_struct());
+        errors.extend(self.check_unterminated_function());
+        errors.extend(self.check_section_stack());
+        errors.extend(self.check_unterminated_conditionals());
+        errors
+    }
+
+    /// Parses every statement in `line`. Macro definitions and invocations are handled here so
+    /// that the rest of the assembler only ever sees expanded code
+    fn parse_line(&mut self, line: &Line) -> Result<()> {
+        // Conditional directives are always parsed to keep track of nesting, everything else is
+        // skipped unless the current block is being assembled
+        if let Some(first) = line.tokens.first()
+            && let Token::Directive(directive) = first.token
+            && directive.is_conditional()
+        {
+            self.current_line = first.span.line;
+            let mut tokens = line.tokens[1..].iter().peekable();
+            return self.parse_conditional(directive, first.span, &mut tokens);
+        }
+        if !self.is_assembling() {
+            return Ok(());
+        }
+
+        // Numeric local labels in a macro definition are resolved each time it's expanded
+        let tokens = match line.tokens.first() {
+            Some(AssemblerToken {
+                token: Token::Directive(Directive::Macro),
+                ..
+            }) => Cow::Borrowed(line.tokens.as_slice()),
+            _ => self.resolve_local_labels(&line.tokens)?,
+        };
+        let mut tokens = tokens.iter().peekable();
+
+        while let Some(token) = tokens.next() {
+            self.current_line = token.span.line;
+
+            match &token.token {
+                Token::Directive(Directive::Macro) => {
+                    return self.parse_macro_definition(token.span, &mut tokens);
+                }
+                Token::Directive(directive) if directive.is_repetition() => {
+                    return self.parse_repetition(*directive, token.span, &mut tokens, line);
+                }
+                Token::Identifier(name) if !matches!(tokens.peek(), Some(next) if matches!(next.token, Token::Colon)) =>
+                {
+                    if let Some(macro_) = self.macros.get(name).cloned() {
+                        return self.expand_macro(&macro_, token.span, &mut tokens, line);
+                    }
+                    self.parse_token(token, &mut tokens).at(token.span)?;
+                }
+                _ => self.parse_token(token, &mut tokens).at(token.span)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the name and source code of the file with the id `file`
+    fn file(&self, file: usize) -> (&str, &str) {
+        match file.checked_sub(1).and_then(|i| self.included.get(i)) {
+            Some(included) => (&included.name, &included.source),
+            None => (&self.filename, &self.source),
+        }
+    }
+
+    /// Formats `error` as a diagnostic pointing into the source code. Errors that don't carry
+    /// their own span point at `fallback` instead. If the error happened inside of a macro, every
+    /// invocation that led to it is shown as well, followed by the `.include`s of the file the
+    /// error is in
+    pub(crate) fn format_error(
+        &self,
+        error: &anyhow::Error,
+        fallback: Span,
+        expansion: Option<&macros::Expansion>,
+    ) -> String {
+        let span = error_span(error).unwrap_or(fallback);
+        let (filename, source) = self.file(span.file);
+        let message = diagnostic::render(filename, source, span, format_args!("{error:#}"));
+        self.add_notes(message, span, expansion)
+    }
+
+    /// Same as `format_error` but for a warning
+    fn format_warning(
+        &self,
+        warning: &anyhow::Error,
+        fallback: Span,
+        expansion: Option<&macros::Expansion>,
+    ) -> String {
+        let span = error_span(warning).unwrap_or(fallback);
+        let (filename, source) = self.file(span.file);
+        let message =
+            diagnostic::render_warning(filename, source, span, format_args!("{warning:#}"));
+        self.add_notes(message, span, expansion)
+    }
+
+    /// Adds the macro invocations and `.include`s that led to `span` to a diagnostic
+    fn add_notes(
+        &self,
+        mut message: String,
+        span: Span,
+        expansion: Option<&macros::Expansion>,
+    ) -> String {
+        let mut expansion = expansion;
+        while let Some(current) = expansion {
+            let (filename, source) = self.file(current.call_span.file);
+            message.push('\n');
+            message.push_str(&diagnostic::render_note(
+                filename,
+                source,
+                current.call_span,
+                format_args!("in expansion of macro `{}`", current.name),
+            ));
+            expansion = current.parent.as_deref();
+        }
+
+        let mut file = span.file;
+        while let Some(included) = file.checked_sub(1).and_then(|i| self.included.get(i)) {
+            let include_span = included.include_span;
+            let (filename, source) = self.file(include_span.file);
+            message.push('\n');
+            message.push_str(&diagnostic::render_note(
+                filename,
+                source,
+                include_span,
+                format_args!("in file included from {filename}"),
+            ));
+            file = include_span.file;
+        }
+
+        message
+    }
+
     fn parse_token<'a>(
         &mut self,
         token: &AssemblerToken,
         tokens: &mut Peekable<impl AsmTokenIter<'a>>,
     ) -> Result<()> {
+        if self.open_struct.is_some() {
+            return self.parse_struct_member(token, tokens);
+        }
+
         match &token.token {
-            Token::Mnemonic(instruction) => self.parse_instruction(&instruction, tokens),
+            Token::Mnemonic(instruction) => {
+                self.parse_instruction(&instruction, token.span, tokens)
+            }
             Token::Directive(directive) => self.parse_directive(*directive, tokens),
             Token::Identifier(id) if id == "." => self.parse_location_counter_assign(tokens),
-            Token::Identifier(id) => self.parse_label(id.clone(), tokens),
+            Token::Identifier(id) => self.parse_label(id.clone(), token.span, tokens),
             Token::Newline => Ok(()),
-            other => Err(anyhow!("Unknown token {other:?}")),
+            other => Err(error_at(token.span, format!("Unknown token {other:?}"))),
         }
     }
 
     fn parse_instruction<'a>(
         &mut self,
         instruction: &Mnemonic,
+        mnemonic_span: Span,
         tokens: &mut Peekable<impl AsmTokenIter<'a>>,
     ) -> Result<()> {
         // All possible instruction encodings of the current mnemonic
@@ -686,6 +1119,7 @@ impl Assembler {
         let mut index_addresses = [MemoryIndex::default(); MAX_OPERANDS];
         let mut reloc_needed = [false; MAX_OPERANDS];
         let mut types = [OperandFlags::empty(); MAX_OPERANDS];
+        let mut spans = [Span::default(); MAX_OPERANDS];
 
         // This is the expression for each operand
         let mut operand_exprs = std::array::from_fn(|_| None);
@@ -697,6 +1131,7 @@ impl Assembler {
             &mut types,
             &mut index_addresses,
             &mut operand_exprs,
+            &mut spans,
         )?;
 
         let mut chosen_encoding: Option<InstEncoding> = None;
@@ -742,7 +1177,11 @@ impl Assembler {
         }
 
         let Some(encoding) = chosen_encoding else {
-            return Err(anyhow!("Invalid instruction"));
+            let span = match operand_count {
+                0 => mnemonic_span,
+                n => mnemonic_span.to(spans[n - 1]),
+            };
+            return Err(error_at(span, "Invalid instruction"));
         };
 
         debug!(
@@ -758,6 +1197,7 @@ impl Assembler {
             operands,
             exprs: operand_exprs,
             reloc: reloc_needed,
+            spans,
         };
 
         let _ = self.emit_instruction(instruction)?;
@@ -786,18 +1226,34 @@ impl Assembler {
             bail!("Expected =, +=, or -=");
         };
 
-        let(section_id, _) = self.sections.get_section()?;
-        let expr = parse_expr(tokens)?;
-        let ExprResult::Constant { constant, section, relocation } = self.evaluate_expression(&expr, section_id)? else {
-            bail!("Cannot assign a register value to the location counter");
+        let (section_id, _) = self.sections.get_section()?;
+        let (expr, span) = parse_spanned_expr(tokens)?;
+        let ExprResult::Constant {
+            constant,
+            section,
+            relocation,
+        } = self.evaluate_expression(&expr, section_id).at(span)?
+        else {
+            return Err(error_at(
+                span,
+                "Cannot assign a register value to the location counter",
+            ));
         };
 
         if relocation {
-            bail!("Cannot use relocatable values when assigning to the location counter");
+            return Err(error_at(
+                span,
+                "Cannot use relocatable values when assigning to the location counter",
+            ));
         }
 
-        if let Some(section) = section && section != section_id {
-            bail!("Cannot assign the location counter to a label from another section");
+        if let Some(section) = section
+            && section != section_id
+        {
+            return Err(error_at(
+                span,
+                "Cannot assign the location counter to a label from another section",
+            ));
         }
 
         let current_section = &mut self.sections[section_id];
@@ -808,6 +1264,7 @@ impl Assembler {
     }
 
     /// TODO: Documentation
+    #[allow(clippy::too_many_arguments)]
     fn parse_operands<'a>(
         &mut self,
         tokens: &mut Peekable<impl AsmTokenIter<'a>>,
@@ -816,6 +1273,7 @@ impl Assembler {
         types: &mut [OperandFlags; MAX_OPERANDS],
         index_addresses: &mut [MemoryIndex; MAX_OPERANDS],
         operand_exprs: &mut [Option<Box<Node>>; MAX_OPERANDS],
+        spans: &mut [Span; MAX_OPERANDS],
     ) -> Result<usize> {
         assert!(
             operands.len() == types.len() && operand_exprs.len() == types.len(),
@@ -831,6 +1289,7 @@ impl Assembler {
         let mut expecting_comma = false;
 
         while let Some(token) = tokens.peek() {
+            let span = token.span;
             let token = &token.token;
             if expecting_comma {
                 if let Token::Comma = token {
@@ -841,7 +1300,7 @@ impl Assembler {
                     expecting_comma = false;
                     break;
                 } else {
-                    return Err(anyhow!("Expected comma"));
+                    return Err(error_at(span, "Expected comma"));
                 }
             } else {
                 expecting_comma = true;
@@ -850,32 +1309,37 @@ impl Assembler {
                 // The operand is a memory index, otherwise it's an expression/register
                 if let Token::LSqrBrace = tokens.peek().context("Expected token")?.token {
                     let _ = tokens.next();
-                    let expr = parse_expr(tokens)?;
+                    let (expr, expr_span) = parse_spanned_expr(tokens)?;
 
-                    let Token::RSqrBrace = tokens
-                        .next()
-                        .context("Expected closing square bracket")?
-                        .token
-                    else {
-                        return Err(anyhow!("Expected closing square bracket"));
+                    let closing = tokens.next().context("Expected closing square bracket")?;
+                    let Token::RSqrBrace = closing.token else {
+                        return Err(error_at(closing.span, "Expected closing square bracket"));
                     };
+                    let span = span.to(closing.span);
 
                     // This function doesn't check if the scalar value is valid, and we won't
                     // either. The emit function will check it.
-                    let (index, relocation) = self.evaluate_memory_index(&expr, current_section)?;
+                    let (index, relocation) = self
+                        .evaluate_memory_index(&expr, current_section)
+                        .at(expr_span)?;
 
                     if let Some(memory_index) = index_addresses.get_mut(num_operands)
                         && let Some(reloc_needed) = reloc_needed.get_mut(num_operands)
                         && let Some(op_type) = types.get_mut(num_operands)
                         && let Some(operand_expr) = operand_exprs.get_mut(num_operands)
+                        && let Some(operand_span) = spans.get_mut(num_operands)
                     {
                         num_operands += 1;
                         *memory_index = index;
                         *reloc_needed = relocation;
                         *op_type = OperandFlags::INDEX;
                         *operand_expr = Some(expr);
+                        *operand_span = span;
                     } else {
-                        return Err(anyhow!("Too many operands. Max is {MAX_OPERANDS}"));
+                        return Err(error_at(
+                            span,
+                            format!("Too many operands. Max is {MAX_OPERANDS}"),
+                        ));
                     }
                 } else {
                     #[derive(Debug, Eq, PartialEq)]
@@ -912,15 +1376,20 @@ impl Assembler {
                         _ => FlagOverride::None,
                     };
 
-                    let expr = parse_expr(tokens)?;
-                    let result = self.evaluate_expression(&expr, current_section)?;
+                    let (expr, expr_span) = parse_spanned_expr(tokens)?;
+                    let span = span.to(expr_span);
+                    let result = self
+                        .evaluate_expression(&expr, current_section)
+                        .at(expr_span)?;
 
                     if let Some(operand) = operands.get_mut(num_operands)
                         && let Some(reloc_needed) = reloc_needed.get_mut(num_operands)
                         && let Some(op_type) = types.get_mut(num_operands)
                         && let Some(operand_expr) = operand_exprs.get_mut(num_operands)
+                        && let Some(operand_span) = spans.get_mut(num_operands)
                     {
                         *operand_expr = Some(expr);
+                        *operand_span = span;
                         num_operands += 1;
                         *reloc_needed = if let ExprResult::Constant { relocation, .. } = result {
                             relocation
@@ -931,7 +1400,10 @@ impl Assembler {
                         (*operand, *op_type) = match result {
                             ExprResult::Register(register) => {
                                 if flag_override != FlagOverride::None {
-                                    bail!("Cannot use operand type specifiers with registers");
+                                    return Err(error_at(
+                                        span,
+                                        "Cannot use operand type specifiers with registers",
+                                    ));
                                 } else {
                                     (Operand::Register(register), register.get_operand_flag())
                                 }
@@ -956,7 +1428,10 @@ impl Assembler {
                             ),
                         };
                     } else {
-                        return Err(anyhow!("Too many operands. Max is {MAX_OPERANDS}"));
+                        return Err(error_at(
+                            span,
+                            format!("Too many operands. Max is {MAX_OPERANDS}"),
+                        ));
                     }
                 }
             }
@@ -965,10 +1440,9 @@ impl Assembler {
         if !expecting_comma {
             Ok(num_operands)
         } else {
-            if tokens.peek().is_none() {
-                Ok(num_operands)
-            } else {
-                Err(anyhow!("Expected comma"))
+            match tokens.peek() {
+                None => Ok(num_operands),
+                Some(token) => Err(error_at(token.span, "Expected comma")),
             }
         }
     }
@@ -976,12 +1450,16 @@ impl Assembler {
     fn parse_label<'a>(
         &mut self,
         name: String,
+        span: Span,
         tokens: &mut Peekable<impl AsmTokenIter<'a>>,
     ) -> Result<()> {
-        let token = &tokens.next().context("Expected token but got EOF")?.token;
+        let token = tokens.next().context("Expected token but got EOF")?;
 
-        let Token::Colon = token else {
-            bail!("Expected colon after identifier but got {token}");
+        let Token::Colon = token.token else {
+            return Err(error_at(
+                token.span,
+                format!("Expected colon after identifier but got {}", token.token),
+            ));
         };
 
         let (current_section, section) = self.sections.get_section_mut()?;
@@ -989,7 +1467,8 @@ impl Assembler {
 
         debug!("Label at {}+{position:#x}", section.name);
         self.symbols
-            .insert_symbol(name, position as u64, Type::Label, Some(current_section))?;
+            .insert_symbol(name, position as u64, Type::Label, Some(current_section))
+            .at(span)?;
 
         Ok(())
     }
@@ -1006,7 +1485,7 @@ impl Assembler {
             bail!("Expected equal sign");
         }
 
-        let expr = parse_expr(tokens)?;
+        let (expr, _) = parse_spanned_expr(tokens)?;
         let (value, relocation) = self.evaluate_non_operand_expression(&expr)?;
 
         if relocation {
@@ -1031,15 +1510,41 @@ mod tests {
     }
 
     use super::*;
+    use crate::section::SectionFlags;
 
     #[allow(dead_code)]
-    fn default_assembler() -> Assembler {
+    pub(super) fn default_assembler() -> Assembler {
         Assembler {
             filename: "test.asm".to_string(),
+            source: String::new(),
+            include_paths: Vec::new(),
+            included: Vec::new(),
             symbols: SymbolTable::new(),
             global_symbols: Vec::new(),
+            extern_symbols: Vec::new(),
+            weak_symbols: Vec::new(),
+            symbol_types: Vec::new(),
+            symbol_sizes: Vec::new(),
+            commons: Vec::new(),
+            strict: false,
+            warnings: Vec::new(),
+            warning_count: 0,
+            warnings_as_errors: false,
             forward_references: Vec::new(),
+            equs: Vec::new(),
+            assertions: Vec::new(),
             sections: SectionMap::new(),
+            lines: Vec::new(),
+            macros: HashMap::new(),
+            macro_count: 0,
+            conditionals: Vec::new(),
+            local_labels: HashMap::new(),
+            local_label_references: Vec::new(),
+            label_scope: None,
+            structs: HashMap::new(),
+            open_struct: None,
+            open_function: None,
+            pushed_sections: Vec::new(),
             current_line: 0,
         }
     }
@@ -1062,6 +1567,415 @@ mod tests {
         let _ = Assembler::assemble(s("test"), source).unwrap_err();
     }
 
+    /// Parses `source` line by line and returns the first error
+    fn first_error(assembler: &mut Assembler, source: &str) -> Option<(anyhow::Error, Line)> {
+        first_error_with_warnings(assembler, source, &mut Vec::new())
+    }
+
+    /// Same as `first_error` but the warnings of every line up to the error are formatted and
+    /// added to `warnings`
+    fn first_error_with_warnings(
+        assembler: &mut Assembler,
+        source: &str,
+        warnings: &mut Vec<String>,
+    ) -> Option<(anyhow::Error, Line)> {
+        assembler.source = source.to_string();
+        let tokens = Assembler::tokenize(source).expect("Source should tokenize");
+        assembler.lines = Line::split(tokens);
+        assembler.lines.reverse();
+
+        while let Some(line) = assembler.lines.pop() {
+            let result = assembler.parse_line(&line);
+            for warning in std::mem::take(&mut assembler.warnings) {
+                let expansion = line.expansion.as_deref();
+                warnings.push(assembler.format_warning(&warning, line.span(), expansion));
+            }
+            if let Err(e) = result {
+                return Some((e, line));
+            }
+        }
+
+        None
+    }
+
+    /// Parses `source` line by line with `assembler` and returns the formatted diagnostic of the
+    /// first error, including the errors that are only found at the end of the source, and the
+    /// formatted warnings up to it
+    pub(super) fn format_diagnostics(
+        assembler: &mut Assembler,
+        source: &str,
+    ) -> (Option<String>, Vec<String>) {
+        let mut warnings = Vec::new();
+        if let Some((e, line)) = first_error_with_warnings(assembler, source, &mut warnings) {
+            let error = assembler.format_error(&e, line.span(), line.expansion.as_deref());
+            return (Some(error), warnings);
+        }
+
+        let error = assembler
+            .check_end_of_source()
+            .into_iter()
+            .next()
+            .map(|e| assembler.format_error(&e, Span::default(), None));
+        (error, warnings)
+    }
+
+    /// Same as `format_diagnostics` without the warnings
+    pub(super) fn format_first_error(assembler: &mut Assembler, source: &str) -> Option<String> {
+        format_diagnostics(assembler, source).0
+    }
+
+    /// Returns the formatted diagnostic of the first error in `source`
+    pub(super) fn first_error_message(source: &str) -> String {
+        format_first_error(&mut default_assembler(), source)
+            .expect("Source should fail to assemble")
+    }
+
+    /// Assembles `source` and builds it into a module
+    pub(super) fn build(source: &str) -> Result<module::Module> {
+        let assembler = Assembler::assemble(String::from("test.asm"), source.to_string())?;
+        module::Module::try_from(assembler)
+    }
+
+    /// Assembles `source` in the `.entry` section and returns its bytes
+    pub(super) fn assemble(source: &str) -> Vec<u8> {
+        let source = format!(".section .entry\n{source}");
+        let assembler =
+            Assembler::assemble(String::from("test.asm"), source).expect("Source should assemble");
+        assembler.sections[0].data.get_ref().clone()
+    }
+
+    /// Parses `source` line by line and returns the span of the first error
+    fn first_error_span(source: &str) -> Span {
+        let (error, line) =
+            first_error(&mut default_assembler(), source).expect("Source should fail to assemble");
+        error_span(&error).unwrap_or(line.span())
+    }
+
+    #[test]
+    fn test_error_spans() {
+        let span = first_error_span(".section .entry\nmov r3, [r1 + r2*3 + 4]");
+        assert_eq!((span.line, span.column), (2, 9));
+        assert_eq!((span.start, span.end), (24, 39));
+
+        let span = first_error_span(".section .entry\nmov r1 r2");
+        assert_eq!((span.line, span.column), (2, 8));
+
+        let span = first_error_span(".section .entry\n.u8 1, (2 + )");
+        assert_eq!((span.line, span.column), (2, 13));
+
+        let span = first_error_span(".section .entry\nmov r0, 1 r1");
+        assert_eq!((span.line, span.column), (2, 11));
+
+        let span = first_error_span(".section .entry\n.equ a, 1\n.equ b, 2 3");
+        assert_eq!((span.line, span.column), (3, 11));
+
+        // Tokens that can't be parsed are reported before anything is assembled
+        let error = Assembler::tokenize(".section .entry\n.u8 1,\n.u8 0b102").unwrap_err();
+        let span = error_span(&error).unwrap();
+        assert_eq!((span.line, span.column), (3, 5));
+        let error = Assembler::assemble(String::from("test.asm"), ".u8 \"\\q\"".to_string())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains(" --> test.asm:1:6"));
+
+        let error = first_error_message(".extern memcpy, .");
+        assert!(error.contains("Cannot use . as a symbol"));
+        assert!(error.contains(" --> test.asm:1:17"));
+        let error = first_error_message(".weak .");
+        assert!(error.contains("Cannot use . as a symbol"));
+        assert!(error.contains(" --> test.asm:1:7"));
+    }
+
+    #[test]
+    fn test_section_flags() {
+        let source = "
+        .section .bss
+        buffer: .skip 64
+        .align 16
+        .section .data, \"r\", 8
+        .section .vars, \"rw nobits\"
+        .skip 4
+        .section .text
+        ";
+        let mut assembler = default_assembler();
+        assert!(first_error(&mut assembler, source).is_none());
+
+        let bss = &assembler.sections[".bss"];
+        assert_eq!(
+            bss.flags,
+            SectionFlags::READ | SectionFlags::WRITE | SectionFlags::NOBITS
+        );
+        assert_eq!((bss.size(), bss.alignment), (64, 16));
+        assert!(bss.data.get_ref().is_empty());
+        let data = &assembler.sections[".data"];
+        assert_eq!((data.flags, data.alignment), (SectionFlags::READ, 8));
+        assert!(assembler.sections[".vars"].is_nobits());
+        assert_eq!(
+            assembler.sections[".text"].flags,
+            SectionFlags::READ | SectionFlags::EXEC
+        );
+
+        let error = |source| {
+            let (error, _) = first_error(&mut default_assembler(), source)
+                .expect("Source should fail to assemble");
+            error.to_string()
+        };
+        assert!(error(".section .bss\n.u8 1").contains("nobits section"));
+        assert!(error(".section .bss\nmov r0, 1").contains("nobits section"));
+        assert!(error(".section .bss\n.skip 4, 1").contains("nobits section"));
+        assert!(error(".section .data\n.section .data, \"rx\"").contains("already declared"));
+        assert!(error(".section .data, \"rq\"").contains("Unknown section flag 'q'"));
+        assert!(error(".section .data, \"r\", 3").contains("must be a power of two"));
+    }
+
+    #[test]
+    fn test_section_stack() {
+        let source = "
+        .macro string name, text
+        .pushsection .rodata
+        \\name: .ascii \\text
+        .popsection
+        .endm
+        .section .entry
+        string hello, \"hi\"
+        halt
+        ";
+        let mut assembler = default_assembler();
+        assert!(first_error(&mut assembler, source).is_none());
+        assert_eq!(assembler.sections[".rodata"].data.get_ref(), b"hi");
+        assert_eq!(assembler.sections[".entry"].size(), 1);
+
+        let (error, _) = first_error(&mut default_assembler(), ".popsection")
+            .expect("Source should fail to assemble");
+        assert!(
+            error
+                .to_string()
+                .contains("without a matching .pushsection")
+        );
+
+        let mut assembler = default_assembler();
+        assert!(first_error(&mut assembler, ".section .text\n.pushsection .data").is_none());
+        let error = assembler.check_section_stack().unwrap();
+        let error = assembler.format_error(&error, Span::default(), None);
+        assert!(error.contains(".pushsection .data is missing its .popsection"));
+        assert!(error.contains(" --> test.asm:2:14"));
+    }
+
+    #[test]
+    fn test_equ_errors() {
+        let error = |source| {
+            let (error, _) = first_error(&mut default_assembler(), source)
+                .expect("Source should fail to assemble");
+            error.to_string()
+        };
+        assert!(error(".equ a, 1\n.set a, 2").contains("Symbol already defined"));
+        assert!(error(".set a, 1\n.equ a, 2").contains("Symbol already defined"));
+        assert!(error(".equ a, later\n.equ a, 1").contains("Symbol already defined"));
+        assert!(error(".set a, later").contains("must be a constant"));
+        assert!(error(".equ a, b\n.equ b, a + 1").contains("b is defined in terms of itself"));
+        assert!(error(".section .entry\n.equ a, later - .").contains("Cannot use ."));
+    }
+
+    #[test]
+    fn test_ascii() {
+        let source = ".section .data\n.ascii \"ab\", 'x', '\\n', 'yz'\n.u8 'x' + 1";
+        let mut assembler = default_assembler();
+        assert!(first_error(&mut assembler, source).is_none());
+        assert_eq!(assembler.sections[".data"].data.get_ref(), b"abx\nyzy");
+    }
+
+    #[test]
+    fn test_floats() {
+        let source = "
+        .section .data
+        .f32 1.5, -2, inf
+        .f64 -1.5e-3, nan
+        ";
+        let mut assembler = default_assembler();
+        assert!(first_error(&mut assembler, source).is_none());
+
+        let mut expected = Vec::new();
+        for value in [1.5f32, -2.0, f32::INFINITY] {
+            expected.extend(value.to_le_bytes());
+        }
+        expected.extend((-1.5e-3f64).to_le_bytes());
+        expected.extend(f64::NAN.to_le_bytes());
+        assert_eq!(assembler.sections[".data"].data.get_ref(), &expected);
+
+        let error = |source| {
+            let (error, _) = first_error(&mut default_assembler(), source)
+                .expect("Source should fail to assemble");
+            error.to_string()
+        };
+        assert!(error(".section .data\n.u32 1.5").contains("Float literals can only be used"));
+        assert!(error(".section .data\n.f32 1.5 + 1").contains("Expected comma"));
+        assert!(error(".section .data\n.f32 1e300").contains("1e300 is too large for .f32"));
+        assert!(error(".section .data\n.f64 label").contains("Expected float"));
+    }
+
+    #[test]
+    fn test_align() {
+        let source = "
+        .section .text
+        halt
+        halt
+        .align 8
+        .section .data
+        .u8 1
+        .align 4, 0xff
+        .u8 2
+        .align 16,, 4
+        .align 8, 0xee, 4
+        ";
+        let mut assembler = default_assembler();
+        assert!(first_error(&mut assembler, source).is_none());
+        assert!(assembler.warnings.is_empty());
+
+        let text = &assembler.sections[".text"];
+        assert_eq!(
+            text.data.get_ref(),
+            &[0, 0, 0x20, 0x00, 0x20, 0x00, 0x20, 0x00]
+        );
+        assert_eq!(text.alignment, 8);
+        let data = &assembler.sections[".data"];
+        assert_eq!(
+            data.data.get_ref(),
+            &[1, 0xff, 0xff, 0xff, 2, 0xee, 0xee, 0xee]
+        );
+        // The skipped `.align 16` doesn't count, but the applied `.align 8` does
+        assert_eq!(data.alignment, 8);
+
+        // Zeros in code would be a `halt`, so padding that can't be no-ops needs a fill byte
+        let (error, _) =
+            format_diagnostics(&mut default_assembler(), ".section .text\nhalt\n.align 4");
+        let error = error.unwrap();
+        assert!(error.contains("3 bytes of padding can't be made of no-ops"));
+        assert!(error.contains(" --> test.asm:3:8"));
+        let mut assembler = default_assembler();
+        assert!(first_error(&mut assembler, ".section .text\nhalt\n.align 4, 0xff").is_none());
+        assert_eq!(
+            assembler.sections[".text"].data.get_ref(),
+            &[0, 0xff, 0xff, 0xff]
+        );
+
+        let error = |source| {
+            let (error, _) = first_error(&mut default_assembler(), source)
+                .expect("Source should fail to assemble");
+            error.to_string()
+        };
+        assert!(error(".section .data\n.align 0").contains("nonzero power of two"));
+        assert!(error(".section .data\n.align 6").contains("nonzero power of two"));
+        assert!(error(".section .bss\n.align 8, 1").contains("nobits section"));
+        assert!(
+            error(".section .data\n.align 4, 0x1ff")
+                .contains("The fill value must be a byte, but it is 511")
+        );
+    }
+
+    /// Evaluates the expression in `source` with an empty assembler
+    fn evaluate(source: &str) -> Result<u64> {
+        evaluate_in(&default_assembler(), source)
+    }
+
+    fn evaluate_in(assembler: &Assembler, source: &str) -> Result<u64> {
+        let tokens = Assembler::tokenize(source).expect("Source should tokenize");
+        let (expr, _) = parse_spanned_expr(&mut tokens.iter().peekable())?;
+        let (value, relocation) = assembler.evaluate_non_operand_expression(&expr)?;
+        assert!(!relocation);
+        Ok(value)
+    }
+
+    #[test]
+    fn test_operators() {
+        assert_eq!(evaluate("1 + 2 * 3").unwrap(), 7);
+        assert_eq!(evaluate("17 % 5").unwrap(), 2);
+        assert_eq!(evaluate("1 << 4 | 1").unwrap(), 17);
+        assert_eq!(evaluate("1 << 2 + 1").unwrap(), 8);
+        assert_eq!(evaluate("0xF0 >> 4 & 0x3").unwrap(), 3);
+        assert_eq!(evaluate("6 & 3 ^ 1 | 8").unwrap(), 11);
+        assert_eq!(evaluate("~0").unwrap(), u64::MAX);
+        assert_eq!(evaluate("!0 + !5").unwrap(), 1);
+        assert_eq!(evaluate("-1 < 0").unwrap(), 1);
+        assert_eq!(evaluate("2 <= 1 || 3 >= 3").unwrap(), 1);
+        assert_eq!(evaluate("1 == 1 && 2 != 2").unwrap(), 0);
+        assert_eq!(evaluate("1 < 2 == 1").unwrap(), 1);
+        assert_eq!(evaluate("1 << 64").unwrap(), 0);
+
+        assert!(evaluate("1 % 0").is_err());
+        assert!(evaluate("1 / 0").is_err());
+    }
+
+    #[test]
+    fn test_builtins() {
+        assert_eq!(evaluate("align_up(13, 8)").unwrap(), 16);
+        assert_eq!(evaluate("align_up(16, 8)").unwrap(), 16);
+        assert_eq!(
+            evaluate("lo32(0x1234_5678_9ABC_DEF0)").unwrap(),
+            0x9ABC_DEF0
+        );
+        assert_eq!(
+            evaluate("hi32(0x1234_5678_9ABC_DEF0)").unwrap(),
+            0x1234_5678
+        );
+        assert_eq!(evaluate("min(-1, 3) + max(2, 7 * 2)").unwrap(), 13);
+        assert_eq!(evaluate("log2(4096) + log2(5)").unwrap(), 14);
+        assert_eq!(evaluate("strlen(\"a\\tb\")").unwrap(), 3);
+        assert_eq!(evaluate("defined(nothing)").unwrap(), 0);
+
+        assert!(evaluate("align_up(1, 0)").is_err());
+        assert!(evaluate("log2(0)").is_err());
+        assert!(evaluate("min(1)").is_err());
+        assert!(evaluate("sizeof(.data)").is_err());
+        assert!(evaluate("lo32(undefined)").is_err());
+
+        let source = "
+        .section .data
+        .align 16
+        table:
+        .u64 1, 2, 3
+        .section .text
+        here:
+        ";
+        let mut assembler = default_assembler();
+        assert!(first_error(&mut assembler, source).is_none());
+
+        assert_eq!(evaluate_in(&assembler, "sizeof(.data) / 8").unwrap(), 3);
+        assert_eq!(evaluate_in(&assembler, "alignof(.data)").unwrap(), 16);
+        assert_eq!(
+            evaluate_in(&assembler, "defined(table) + defined(here)").unwrap(),
+            2
+        );
+        // Labels are only known relative to their section
+        assert!(evaluate_in(&assembler, "align_up(here, 8)").is_err());
+    }
+
+    #[test]
+    fn test_label_operators() {
+        let source = s("
+        .section .entry
+        start:
+        .u8 1, 2, 3
+        end:
+        .u8 end - start, (end - start) << 1
+        ");
+        let _ = Assembler::assemble(s("test"), source).unwrap();
+
+        let source = s("
+        .section .entry
+        start:
+        mov r0, start & 0xFF
+        ");
+        let _ = Assembler::assemble(s("test"), source).unwrap_err();
+
+        let source = s("
+        .section .entry
+        start:
+        mov r0, [start << 1]
+        ");
+        let _ = Assembler::assemble(s("test"), source).unwrap_err();
+    }
+
     #[test]
     fn test_memory_index() {
         // let mut assembler = default_assembler();
@@ -0,0 +1,171 @@
+use std::iter::Peekable;
+
+use anyhow::{Result, bail};
+
+use crate::{
+    assembler::{
+        AsmTokenIter, Assembler,
+        directive::{expect_comma, should_return_none},
+    },
+    diagnostic::{Span, SpanContext, error_at},
+    expression::{Node, parse_spanned_expr},
+};
+
+/// An `.assert` that couldn't be checked while assembling
+#[derive(Debug)]
+pub struct Assertion {
+    pub expr: Box<Node>,
+    /// The section and offset of `.` if the expression refers to it
+    pub location: Option<(usize, u64)>,
+    /// The section the `.assert` was in, used for builtins like `sizeof`
+    pub section: usize,
+    /// The error shown if the assertion fails
+    pub message: String,
+    pub span: Span,
+}
+
+impl Assembler {
+    /// Parses `.assert expr, "message"`. The message is optional.
+    ///
+    /// The assertion is checked right away if `expr` is a constant, otherwise once the module is
+    /// built or, if it depends on where labels end up, once it's linked
+    pub(super) fn parse_assert<'a>(
+        &mut self,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<()> {
+        if should_return_none(tokens) {
+            bail!("Expected expression");
+        }
+        let (mut expr, span) = parse_spanned_expr(tokens)?;
+        expect_comma(tokens)?;
+
+        let message = match self.parse_string_argument(tokens)? {
+            Some((message, _)) => {
+                format!("Assertion failed: {}", String::from_utf8_lossy(&message))
+            }
+            None => String::from("Assertion failed"),
+        };
+
+        match self.evaluate_non_operand_expression(&expr) {
+            Ok((0, false)) => return Err(error_at(span, message)),
+            Ok((_, false)) => return Ok(()),
+            Ok((_, true)) => {}
+            // Operations like `&` on labels are fine once their address is known
+            Err(e) if !self.refers_to_labels(&expr) => return Err(e).at(span),
+            Err(_) => {}
+        }
+
+        // `.` has to stay the location of the `.assert` even though the cursor moves on
+        let mut location_counter = false;
+        expr.for_each_identifier(&mut |id| location_counter |= id == ".");
+        let location = if location_counter {
+            let (section, cursor) = self.sections.cursor()?;
+            Some((section, cursor as u64))
+        } else {
+            None
+        };
+        expr.substitute(&|id| self.symbols.get_variable(id).map(Node::Constant));
+
+        let section = self
+            .sections
+            .get_section()
+            .map_or(Self::NO_SECTION, |(section, _)| section);
+        self.assertions.push(Assertion {
+            expr,
+            location,
+            section,
+            message,
+            span,
+        });
+
+        Ok(())
+    }
+
+    /// Whether `expr` refers to a label, the location counter or a symbol that isn't defined yet
+    fn refers_to_labels(&self, expr: &Node) -> bool {
+        let mut labels = false;
+        expr.for_each_identifier(&mut |id| {
+            labels |= id == "."
+                || self
+                    .symbols
+                    .get_symbol(id)
+                    .is_none_or(|symbol| symbol.section_index.is_some());
+        });
+        labels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assembler::tests::{build, default_assembler, format_first_error},
+        linker::{Instr, link},
+    };
+
+    /// Returns the formatted diagnostic of the first error in `source`
+    fn first_error(source: &str) -> Option<String> {
+        format_first_error(&mut default_assembler(), source)
+    }
+
+    #[test]
+    fn test_constant() {
+        assert!(first_error(".assert 1 + 1 == 2, \"math works\"\n.assert 3").is_none());
+
+        let error = first_error(".equ N, 3\n.assert N < 2, \"N is too large\"").unwrap();
+        assert!(error.contains("Assertion failed: N is too large"));
+        assert!(error.contains(" --> test.asm:2:9"));
+
+        assert!(
+            first_error(".assert 0")
+                .unwrap()
+                .contains("Assertion failed")
+        );
+        assert!(
+            first_error(".assert 1 / 0")
+                .unwrap()
+                .contains("Division by zero")
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        let source = ".section .entry\n.assert end - start <= 2, \"table is too large\"\nstart: .u8 1, 2\nend:";
+        assert!(build(source).is_ok());
+
+        let source = ".section .entry\n.assert end - start <= 1, \"table is too large\"\nstart: .u8 1, 2\nend:";
+        let error = build(source).err().unwrap().to_string();
+        assert!(error.contains("Assertion failed: table is too large"));
+        assert!(error.contains(" --> test.asm:2:9"));
+
+        // `.` is where the `.assert` is, without adding a symbol to the module
+        let source = ".section .entry\nstart: .u8 1, 2\n.assert . - start == 2\n.u8 3";
+        let module = build(source).expect("Module should build");
+        assert!(module.assertions.is_empty());
+        assert_eq!(module.symbols.iter().count(), 1);
+        let source = ".section .entry\nstart: .u8 1, 2\n.assert . - start == 3\n.u8 3";
+        assert!(build(source).is_err());
+    }
+
+    #[test]
+    fn test_addresses() {
+        let linked = |data: &str| {
+            let source = format!(".section .entry\n.u8 1\n.section .data\n{data}");
+            let script = vec![
+                Instr::Section(".entry".to_string()),
+                Instr::Section(".data".to_string()),
+            ];
+            link(vec![build(&source).expect("Module should build")], script)
+        };
+
+        let aligned = ".align 8\ntable: .u64 0\n.assert (table & 7) == 0, \"table is misaligned\"";
+        assert!(linked(aligned).is_ok());
+        let misaligned = "table: .u64 0\n.assert (table & 7) == 0, \"table is misaligned\"";
+        assert!(linked(misaligned).is_err());
+
+        assert!(linked(".align 4\n.assert (. & 3) == 0\n.u8 1").is_ok());
+        assert!(linked(".align 4\n.u8 1\n.assert (. & 3) == 0").is_err());
+
+        let program = linked(".align 4\n.assert (. & 3) == 0\n.u8 1").unwrap();
+        assert!(program.symbols().is_empty());
+    }
+}
@@ -0,0 +1,162 @@
+use std::iter::Peekable;
+
+use anyhow::{Context, Result};
+
+use crate::{
+    assembler::{AsmTokenIter, Assembler, symbol_table::Type},
+    diagnostic::{Span, SpanContext, error_at},
+    module::Common,
+};
+
+impl Assembler {
+    /// Parses `.comm name, size, align`, which declares an uninitialized global that several
+    /// modules can declare. The linker merges them and allocates the space
+    pub(super) fn parse_comm<'a>(
+        &mut self,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<()> {
+        let (common, span) = self.parse_common_arguments(tokens)?;
+        self.commons.push((common, span));
+
+        Ok(())
+    }
+
+    /// Parses `.lcomm name, size, align`, which reserves space for `name` in the `.bss` of this
+    /// module. Unlike `.comm` the symbol is local
+    pub(super) fn parse_lcomm<'a>(
+        &mut self,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<()> {
+        let (common, span) = self.parse_common_arguments(tokens)?;
+
+        self.sections.push_section(".bss", None)?;
+        let (section, bss) = self.sections.get_section_mut()?;
+        bss.align(common.alignment).at(span)?;
+        let address = bss.cursor() as u64;
+        bss.reserve(common.size);
+        self.sections.pop_section()?;
+
+        self.symbols
+            .insert_symbol(common.name.clone(), address, Type::Object, Some(section))
+            .at(span)?;
+        if let Some(symbol) = self.symbols.get_symbol_mut(&common.name) {
+            symbol.size = Some(common.size);
+        }
+
+        Ok(())
+    }
+
+    /// Parses the name, size and optional alignment of `.comm` and `.lcomm`. Without an
+    /// alignment the symbol is aligned to the largest power of two up to 16 that fits in its size
+    fn parse_common_arguments<'a>(
+        &mut self,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<(Common, Span)> {
+        let span = tokens.peek().map(|token| token.span).unwrap_or_default();
+        let name = self
+            .parse_identifier_argument(tokens)?
+            .context("Expected symbol name")?;
+        if name == "." {
+            return Err(error_at(span, "Cannot use . as a symbol"));
+        }
+
+        let (size, relocation, _, size_span) =
+            self.parse_expr_argument(tokens)?.context("Expected size")?;
+        if relocation {
+            return Err(error_at(size_span, "Size must be a constant"));
+        }
+
+        let alignment = match self.parse_expr_argument(tokens)? {
+            Some((_, true, _, span)) => {
+                return Err(error_at(span, "Alignment must be a constant"));
+            }
+            Some((alignment, false, _, span)) if !alignment.is_power_of_two() => {
+                return Err(error_at(
+                    span,
+                    format!("Alignment must be a nonzero power of two, but it is {alignment}"),
+                ));
+            }
+            Some((alignment, ..)) => alignment,
+            None if size == 0 => 1,
+            None => 1 << size.ilog2().min(4),
+        };
+
+        let common = Common {
+            name,
+            size,
+            alignment,
+        };
+        Ok((common, span))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assembler::{
+            Assembler,
+            symbol_table::Type,
+            tests::{build, first_error_message},
+        },
+        module::Module,
+    };
+
+    #[test]
+    fn test_comm() {
+        let source = "
+        .comm buffer, 64, 8
+        .comm flags, 4
+        .comm buffer, 128
+        .global buffer
+        .section .entry
+        .u8 1
+        .lcomm scratch, 3
+        .lcomm counter, 8, 8
+        .u32 buffer
+        ";
+        let module = build(source).expect("Module should build");
+
+        let names: Vec<_> = module
+            .commons
+            .iter()
+            .map(|common| (common.name.as_str(), common.size, common.alignment))
+            .collect();
+        assert_eq!(names, &[("buffer", 128, 16), ("flags", 4, 4)]);
+        assert!(module.global_symbols.is_empty());
+        assert!(module.symbols.get_symbol("buffer").is_none());
+
+        // `.lcomm` doesn't leave the current section
+        assert_eq!(module.sections[".entry"].size(), 5);
+        let bss = &module.sections[".bss"];
+        assert_eq!((bss.size(), bss.alignment), (16, 8));
+        let counter = module.symbols.get_symbol("counter").unwrap();
+        assert_eq!((counter.value, counter.type_), (8, Type::Object));
+        assert_eq!(counter.size, Some(8));
+        assert_eq!(module.symbols.get_symbol("scratch").unwrap().value, 0);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = build(".comm a, 8\n.section .data\na: .u64 0")
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("a is a common symbol, so it can't be defined in this module"));
+        assert!(error.contains(" --> test.asm:1:7"));
+
+        let mut assembler = Assembler::assemble(
+            String::from("test.asm"),
+            ".comm a, 8\n.section .data\n.u64 a".to_string(),
+        )
+        .unwrap();
+        assembler.strict = true;
+        assert!(Module::try_from(assembler).is_ok());
+
+        let error = first_error_message(".comm ., 4");
+        assert!(error.contains("Cannot use . as a symbol"));
+        assert!(error.contains(" --> test.asm:1:7"));
+
+        assert!(build(".comm a, 8, 3").is_err());
+        assert!(build(".comm a, later").is_err());
+    }
+}
@@ -0,0 +1,271 @@
+use std::iter::Peekable;
+
+use anyhow::Result;
+
+use crate::{
+    assembler::{AsmTokenIter, Assembler, directive::expect_end_of_line},
+    diagnostic::{Span, SpanContext, error_at},
+    tokens::Directive,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// The current branch is being assembled
+    Active,
+    /// No branch has been taken yet, so a later `.elif` or `.else` can still be
+    Pending,
+    /// A branch was already taken, or the whole block is inside of a branch that isn't assembled
+    Done,
+}
+
+/// An `.if` block that hasn't reached its `.endif` yet
+#[derive(Debug)]
+pub struct Conditional {
+    /// The directive that opened the block
+    directive: Directive,
+    span: Span,
+    state: State,
+    else_seen: bool,
+}
+
+impl Conditional {
+    fn opening(&self) -> String {
+        format!("the {} on line {}", self.directive.name(), self.span.line)
+    }
+}
+
+impl Assembler {
+    /// Whether lines are currently being assembled or skipped by a conditional block
+    pub(super) fn is_assembling(&self) -> bool {
+        self.conditionals
+            .last()
+            .is_none_or(|conditional| conditional.state == State::Active)
+    }
+
+    /// Parses a conditional directive at the start of a line. These are handled even while lines
+    /// are being skipped to keep track of nesting, but conditions are only evaluated when needed
+    pub(super) fn parse_conditional<'a>(
+        &mut self,
+        directive: Directive,
+        span: Span,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<()> {
+        match directive {
+            Directive::If | Directive::Ifdef | Directive::Ifndef => {
+                let state = if self.is_assembling() {
+                    match self.parse_condition(directive, span, tokens)? {
+                        true => State::Active,
+                        false => State::Pending,
+                    }
+                } else {
+                    State::Done
+                };
+
+                self.conditionals.push(Conditional {
+                    directive,
+                    span,
+                    state,
+                    else_seen: false,
+                });
+            }
+            Directive::Elif => {
+                let conditional = self.innermost_conditional(directive, span)?;
+                if conditional.else_seen {
+                    let opening = conditional.opening();
+                    return Err(error_at(
+                        span,
+                        format!(".elif after the .else of {opening}"),
+                    ));
+                }
+
+                match conditional.state {
+                    State::Active => conditional.state = State::Done,
+                    State::Pending => {
+                        let state = match self.parse_condition(directive, span, tokens)? {
+                            true => State::Active,
+                            false => State::Pending,
+                        };
+                        self.conditionals
+                            .last_mut()
+                            .expect("The conditional was checked above")
+                            .state = state;
+                    }
+                    State::Done => {}
+                }
+            }
+            Directive::Else => {
+                let conditional = self.innermost_conditional(directive, span)?;
+                if conditional.else_seen {
+                    let opening = conditional.opening();
+                    return Err(error_at(span, format!("Second .else for {opening}")));
+                }
+
+                conditional.else_seen = true;
+                conditional.state = match conditional.state {
+                    State::Active | State::Done => State::Done,
+                    State::Pending => State::Active,
+                };
+                expect_end_of_line(tokens)?;
+            }
+            Directive::Endif => {
+                self.innermost_conditional(directive, span)?;
+                self.conditionals.pop();
+                expect_end_of_line(tokens)?;
+            }
+            _ => unreachable!("{} is not a conditional directive", directive.name()),
+        }
+
+        Ok(())
+    }
+
+    /// Reports every `.if` block that was never closed
+    pub(super) fn check_unterminated_conditionals(&mut self) -> Vec<anyhow::Error> {
+        self.conditionals
+            .drain(..)
+            .map(|conditional| {
+                error_at(
+                    conditional.span,
+                    format!("{} is missing its .endif", conditional.directive.name()),
+                )
+            })
+            .collect()
+    }
+
+    fn innermost_conditional(
+        &mut self,
+        directive: Directive,
+        span: Span,
+    ) -> Result<&mut Conditional> {
+        self.conditionals
+            .last_mut()
+            .ok_or_else(|| error_at(span, format!("{} without a matching .if", directive.name())))
+    }
+
+    /// Evaluates the condition of an `.if`, `.elif`, `.ifdef` or `.ifndef`
+    fn parse_condition<'a>(
+        &mut self,
+        directive: Directive,
+        span: Span,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<bool> {
+        let condition = match directive {
+            Directive::Ifdef | Directive::Ifndef => {
+                let symbol_span = tokens.peek().map(|token| token.span).unwrap_or(span);
+                let symbol = self
+                    .parse_identifier_argument(tokens)?
+                    .ok_or_else(|| error_at(span, "Expected a symbol name"))?;
+                if symbol == "." {
+                    return Err(error_at(symbol_span, "Cannot use . as a symbol"));
+                }
+                let defined = self.symbols.get_symbol(&symbol).is_some();
+                defined == (directive == Directive::Ifdef)
+            }
+            _ => {
+                let (value, relocation, _, expr_span) = self
+                    .parse_expr_argument(tokens)?
+                    .ok_or_else(|| error_at(span, "Expected a condition"))?;
+                if relocation {
+                    return Err(error_at(
+                        expr_span,
+                        "Condition must be a constant, but its value isn't known yet",
+                    ));
+                }
+                value != 0
+            }
+        };
+
+        expect_end_of_line(tokens).at(span)?;
+        Ok(condition)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::tests::{assemble, first_error_message};
+
+    #[test]
+    fn test_branches() {
+        let source = "
+        .equ DEBUG, 1
+        .if DEBUG
+        .u8 1
+        .else
+        .u8 2
+        .endif
+        .if DEBUG == 0
+        .u8 3
+        .elif DEBUG == 1
+        .u8 4
+        .elif 1
+        .u8 5
+        .else
+        .u8 6
+        .endif
+        .ifdef DEBUG
+        .u8 7
+        .endif
+        .ifndef DEBUG
+        .u8 8
+        .endif
+        ";
+        assert_eq!(assemble(source), &[1, 4, 7]);
+    }
+
+    #[test]
+    fn test_nesting() {
+        let source = "
+        .if 0
+        .if undefined_symbol
+        .u8 1
+        .else
+        .u8 2
+        .endif
+        this line is never parsed
+        .else
+        .if 1
+        .u8 3
+        .endif
+        .endif
+        ";
+        assert_eq!(assemble(source), &[3]);
+
+        // Macros are only defined and expanded in assembled blocks
+        let source = "
+        .if 0
+        .macro byte
+        .u8 1
+        .endm
+        .else
+        .macro byte
+        .if 1
+        .u8 2
+        .endif
+        .endm
+        .endif
+        byte
+        ";
+        assert_eq!(assemble(source), &[2]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = first_error_message(".if 1\n.else\n.else\n.endif");
+        assert!(error.contains("Second .else for the .if on line 1"));
+        assert!(error.contains(" --> test.asm:3:1"));
+
+        let error = first_error_message(".equ B, 1\n.ifdef A\n.if 1\n.endif");
+        assert!(error.contains(".ifdef is missing its .endif"));
+        assert!(error.contains(" --> test.asm:2:1"));
+
+        assert!(first_error_message(".else").contains(".else without a matching .if"));
+        assert!(first_error_message(".endif").contains(".endif without a matching .if"));
+        assert!(first_error_message(".if 1\n.else\n.elif 1").contains(".elif after the .else"));
+        assert!(
+            first_error_message(".if later\n.endif\n.equ later, 1").contains("must be a constant")
+        );
+
+        let error = first_error_message(".ifdef .\n.endif");
+        assert!(error.contains("Cannot use . as a symbol"));
+        assert!(error.contains(" --> test.asm:1:8"));
+    }
+}
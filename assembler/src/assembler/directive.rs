@@ -1,19 +1,18 @@
-use std::{
-    iter::{self, Peekable},
-    rc::Rc,
-};
+use std::{iter::Peekable, rc::Rc};
 
 use crate::{
     assembler::{
-        AsmTokenIter, Assembler, AssemblerToken, ForwardReferenceEntry, symbol_table::Type,
+        AsmTokenIter, Assembler, AssemblerToken, Equ, ExprResult, ForwardReferenceEntry,
+        symbol_table::Type,
     },
-    expression::{Node, parse_expr},
+    diagnostic::{Span, SpanContext, error_at},
+    expression::{Node, parse_spanned_expr},
     opcode::Relocation,
-    section,
+    section::SectionFlags,
     size::Size,
-    tokens::{self, Directive, Token},
+    tokens::{Directive, Token},
 };
-use anyhow::{Context, Result, anyhow, bail};
+use anyhow::{Context, Result, bail};
 use strum::EnumDiscriminants;
 
 #[derive(Debug, EnumDiscriminants)]
@@ -27,7 +26,11 @@ enum DirectiveArgument {
     Identifier(String),
 }
 
-fn should_return_none<'a>(tokens: &mut Peekable<impl AsmTokenIter<'a>>) -> bool {
+/// The value of an expression argument, whether it needs to be relocated, the expression itself
+/// and where it is in the source code
+type ExprArgument = (u64, bool, Box<Node>, Span);
+
+pub(super) fn should_return_none<'a>(tokens: &mut Peekable<impl AsmTokenIter<'a>>) -> bool {
     match tokens.peek() {
         None
         | Some(AssemblerToken {
@@ -38,44 +41,56 @@ fn should_return_none<'a>(tokens: &mut Peekable<impl AsmTokenIter<'a>>) -> bool
     }
 }
 
-fn valid_comma<'a>(tokens: &mut Peekable<impl AsmTokenIter<'a>>) -> bool {
+/// Consumes the comma after an argument. The last argument is followed by a newline or EOF
+/// instead, which is left unconsumed
+pub(super) fn expect_comma<'a>(tokens: &mut Peekable<impl AsmTokenIter<'a>>) -> Result<()> {
     match tokens.peek() {
         None
         | Some(AssemblerToken {
             token: Token::Newline,
             ..
-        }) => true,
+        }) => Ok(()),
         Some(AssemblerToken {
             token: Token::Comma,
             ..
         }) => {
             _ = tokens.next();
-            true
+            Ok(())
         }
-        _ => false,
+        Some(token) => Err(error_at(token.span, "Expected comma")),
+    }
+}
+
+/// A directive must consist of the entire line, so the next token has to be a newline or EOF
+pub(super) fn expect_end_of_line<'a>(tokens: &mut Peekable<impl AsmTokenIter<'a>>) -> Result<()> {
+    match tokens.next() {
+        None
+        | Some(AssemblerToken {
+            token: Token::Newline,
+            ..
+        }) => Ok(()),
+        Some(token) => Err(error_at(token.span, "Unexpected token")),
     }
 }
 
 impl Assembler {
-    fn parse_expr_argument<'a>(
+    pub(super) fn parse_expr_argument<'a>(
         &self,
         tokens: &mut Peekable<impl AsmTokenIter<'a>>,
-    ) -> Result<Option<(u64, bool, Box<Node>)>> {
+    ) -> Result<Option<ExprArgument>> {
         if should_return_none(tokens) {
             return Ok(None);
         }
 
-        let expr = parse_expr(tokens)?;
-        let (value, relocation) = self.evaluate_non_operand_expression(&expr)?;
+        let (expr, span) = parse_spanned_expr(tokens)?;
+        let (value, relocation) = self.evaluate_non_operand_expression(&expr).at(span)?;
 
-        if !valid_comma(tokens) {
-            bail!("Expected comma");
-        }
+        expect_comma(tokens)?;
 
-        Ok(Some((value, relocation, expr)))
+        Ok(Some((value, relocation, expr, span)))
     }
 
-    fn parse_identifier_argument<'a>(
+    pub(super) fn parse_identifier_argument<'a>(
         &self,
         tokens: &mut Peekable<impl AsmTokenIter<'a>>,
     ) -> Result<Option<String>> {
@@ -83,42 +98,32 @@ impl Assembler {
             return Ok(None);
         }
 
-        let Some(AssemblerToken {
-            token: Token::Identifier(id),
-            ..
-        }) = tokens.next()
-        else {
-            bail!("Expected identifier");
+        let token = tokens.next().context("Expected identifier")?;
+        let Token::Identifier(id) = &token.token else {
+            return Err(error_at(token.span, "Expected identifier"));
         };
 
-        if !valid_comma(tokens) {
-            bail!("Expected comma");
-        }
+        expect_comma(tokens)?;
 
         Ok(Some(id.clone()))
     }
 
-    fn parse_string_argument<'a>(
+    pub(super) fn parse_string_argument<'a>(
         &self,
         tokens: &mut Peekable<impl AsmTokenIter<'a>>,
-    ) -> Result<Option<Rc<str>>> {
+    ) -> Result<Option<(Rc<[u8]>, Span)>> {
         if should_return_none(tokens) {
             return Ok(None);
         }
 
-        let Some(AssemblerToken {
-            token: Token::Ascii(string),
-            ..
-        }) = tokens.next()
-        else {
-            bail!("Expected string");
+        let token = tokens.next().context("Expected string")?;
+        let (Token::Ascii(string) | Token::Character(_, string)) = &token.token else {
+            return Err(error_at(token.span, "Expected string"));
         };
 
-        if !valid_comma(tokens) {
-            bail!("Expected comma");
-        }
+        expect_comma(tokens)?;
 
-        Ok(Some(string.clone()))
+        Ok(Some((string.clone(), token.span)))
     }
 
     pub(super) fn parse_directive<'a>(
@@ -128,81 +133,65 @@ impl Assembler {
     ) -> Result<()> {
         match directive {
             Directive::Section => self.parse_section_directive(tokens),
+            Directive::Pushsection => self.parse_pushsection(tokens),
+            Directive::Popsection => self.parse_popsection(tokens),
+            Directive::Previous => self.sections.previous(),
             Directive::Equ => self.parse_equ(tokens),
+            Directive::Set => self.parse_set(tokens),
             Directive::Align => self.parse_section_align(tokens),
             Directive::Skip => self.parse_skip(tokens),
             Directive::Global => self.parse_global_directive(tokens),
+            Directive::Extern => self.parse_extern(tokens),
+            Directive::Weak => self.parse_weak(tokens),
+            Directive::Comm => self.parse_comm(tokens),
+            Directive::Lcomm => self.parse_lcomm(tokens),
+            Directive::Type => self.parse_type(tokens),
+            Directive::Size => self.parse_size(tokens),
+            Directive::Func => self.parse_func(tokens),
+            Directive::Endfunc => self.parse_endfunc(tokens),
+            Directive::Assert => self.parse_assert(tokens),
+            Directive::Error => self.parse_error(tokens),
+            Directive::Warning => self.parse_warning(tokens),
+            Directive::Print => self.parse_print(tokens),
             Directive::U8 => self.parse_embed(Size::U8, tokens),
             Directive::U16 => self.parse_embed(Size::U16, tokens),
             Directive::U32 => self.parse_embed(Size::U32, tokens),
             Directive::U64 => self.parse_embed(Size::U64, tokens),
+            Directive::F32 => self.parse_float(false, tokens),
+            Directive::F64 => self.parse_float(true, tokens),
             Directive::Ascii => self.parse_ascii(tokens),
+            Directive::Include => self.parse_include(tokens),
+            Directive::Incbin => self.parse_incbin(tokens),
+            // Macro definitions are collected line by line before a statement is parsed
+            Directive::Macro => bail!(".macro must be at the start of a line"),
+            Directive::Endm => bail!(".endm without a matching .macro"),
+            Directive::If
+            | Directive::Ifdef
+            | Directive::Ifndef
+            | Directive::Elif
+            | Directive::Else
+            | Directive::Endif
+            | Directive::Rept
+            | Directive::Irp
+            | Directive::Irpc => bail!("{} must be at the start of a line", directive.name()),
+            Directive::Endr => bail!(".endr without a matching .rept, .irp or .irpc"),
+            Directive::Struct => self.parse_struct(tokens),
+            Directive::Endstruct => bail!(".endstruct without a matching .struct"),
+            Directive::Instance => self.parse_instance(tokens),
         }?;
 
-        // A directive must consist of the entire line, if not then it is an error
-        match tokens.next() {
-            None
-            | Some(AssemblerToken {
-                token: Token::Newline,
-                ..
-            }) => {}
-            _ => bail!("Unexpected token"),
-        }
-
-        Ok(())
+        expect_end_of_line(tokens)
     }
 
     fn parse_ascii<'a>(&mut self, tokens: &mut Peekable<impl AsmTokenIter<'a>>) -> Result<()> {
-        fn escape_char(chars: &mut impl Iterator<Item = char>) -> Result<u8> {
-            match chars.next() {
-                None => bail!("'\\' must be followed by an escape character"),
-                Some(c) => match c {
-                    'n' => Ok(b'\n'),
-                    '\'' => Ok(b'\''),
-                    '\"' => Ok(b'\"'),
-                    'a' => Ok(0x07),
-                    'b' => Ok(0x08),
-                    'f' => Ok(0x0c),
-                    '\r' => Ok(b'\r'),
-                    '\t' => Ok(b'\t'),
-                    'v' => Ok(0x0b),
-                    '\\' => Ok(b'\\'),
-                    '0' => Ok(0),
-                    'x' => {
-                        let mut total = 0;
-                        for i in (0..2).rev() {
-                            if let Some(c) = chars.next()
-                                && let Some(digit) = c.to_digit(16)
-                            {
-                                total += (digit as u8) << (4 * i);
-                            } else {
-                                break;
-                            }
-                        }
-
-                        Ok(total)
-                    }
-                    _ => bail!("Invalid escape character '{c}'"),
-                },
-            }
-        }
         let mut count = 0usize;
 
-        while let Some(string) = self.parse_string_argument(tokens)? {
+        // Escape sequences were already decoded by the lexer
+        while let Some((string, _)) = self.parse_string_argument(tokens)? {
             count += 1;
 
-            let (_, section) = self.sections.get_section_mut()?;
-            let mut chars = string.chars();
-            while let Some(c) = chars.next() {
-                let mut buf = [0; 4];
-                if c == '\\' {
-                    let escaped = escape_char(&mut chars)?;
-                    section.write_u8(escaped);
-                } else {
-                    let slice = c.encode_utf8(&mut buf);
-                    section.write_bytes(slice.as_bytes());
-                }
-            }
+            let (_, section) = self.sections.get_data_section_mut()?;
+            section.write_bytes(&string);
         }
 
         if count > 0 {
@@ -217,36 +206,65 @@ impl Assembler {
         size: Size,
         tokens: &mut Peekable<impl AsmTokenIter<'a>>,
     ) -> Result<()> {
-        let relocation_kind = match size {
-            Size::U8 => Relocation::Abs8,
-            Size::U16 => Relocation::Abs16,
-            Size::U32 => Relocation::Abs32,
-            Size::U64 => Relocation::Abs64,
-        };
-
         let mut count = 0usize;
-        while let Some((value, relocation, expr)) = self.parse_expr_argument(tokens)? {
+        while let Some((value, relocation, expr, span)) = self.parse_expr_argument(tokens)? {
             count += 1;
-            let (section_id, section) = self.sections.get_section_mut()?;
-            if relocation {
-                let cursor = section.cursor();
+            self.embed_value(size, value, relocation, expr, span)?;
+        }
+        if count > 0 {
+            Ok(())
+        } else {
+            bail!("Expected one or more arguments")
+        }
+    }
 
-                let entry = ForwardReferenceEntry::new(
-                    relocation_kind,
-                    section_id,
-                    cursor,
-                    expr,
-                    self.current_line,
-                );
-                self.forward_references.push(entry);
+    /// Parses `.f32` or `.f64` followed by float literals, integers, `inf` or `nan`, each with an
+    /// optional sign, and writes them as IEEE-754 floats
+    fn parse_float<'a>(
+        &mut self,
+        double: bool,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<()> {
+        let mut count = 0usize;
+        while !should_return_none(tokens) {
+            let mut sign = 1.0;
+            if let Some(AssemblerToken {
+                token: token @ (Token::Sub | Token::Plus),
+                ..
+            }) = tokens.peek()
+            {
+                if matches!(token, Token::Sub) {
+                    sign = -1.0;
+                }
+                _ = tokens.next();
             }
 
-            match size {
-                Size::U8 => section.write_u8(value as u8),
-                Size::U16 => section.write_u16(value as u16),
-                Size::U32 => section.write_u32(value as u32),
-                Size::U64 => section.write_u64(value as u64),
+            let token = tokens.next().context("Expected float")?;
+            let value = match &token.token {
+                Token::Float(value) => *value,
+                Token::Number(value) => *value as f64,
+                Token::Identifier(id) if id == "inf" => f64::INFINITY,
+                Token::Identifier(id) if id == "nan" => f64::NAN,
+                _ => return Err(error_at(token.span, "Expected float")),
+            };
+            let value = sign * value;
+
+            let (_, section) = self.sections.get_data_section_mut()?;
+            if double {
+                section.write_bytes(&value.to_le_bytes());
+            } else {
+                let single = value as f32;
+                if single.is_infinite() && value.is_finite() {
+                    return Err(error_at(
+                        token.span,
+                        format!("{value:e} is too large for .f32"),
+                    ));
+                }
+                section.write_bytes(&single.to_le_bytes());
             }
+
+            count += 1;
+            expect_comma(tokens)?;
         }
         if count > 0 {
             Ok(())
@@ -255,80 +273,322 @@ impl Assembler {
         }
     }
 
+    /// Writes `value` into the current section, and records a forward reference to fill it in
+    /// later if it needs to be relocated
+    pub(super) fn embed_value(
+        &mut self,
+        size: Size,
+        value: u64,
+        relocation: bool,
+        expr: Box<Node>,
+        span: Span,
+    ) -> Result<()> {
+        let relocation_kind = match size {
+            Size::U8 => Relocation::Abs8,
+            Size::U16 => Relocation::Abs16,
+            Size::U32 => Relocation::Abs32,
+            Size::U64 => Relocation::Abs64,
+        };
+
+        let (section_id, section) = self.sections.get_data_section_mut()?;
+        if relocation {
+            let cursor = section.cursor();
+
+            let entry = ForwardReferenceEntry::new(relocation_kind, section_id, cursor, expr, span);
+            self.forward_references
+                .push(entry.freeze_variables(&self.symbols));
+        }
+
+        match size {
+            Size::U8 => section.write_u8(value as u8),
+            Size::U16 => section.write_u16(value as u16),
+            Size::U32 => section.write_u32(value as u32),
+            Size::U64 => section.write_u64(value as u64),
+        }
+
+        Ok(())
+    }
+
+    /// Parses `.equ name, value`. A label or a value that isn't known yet makes `name` an alias,
+    /// which is resolved once the module is built
     fn parse_equ<'a>(&mut self, tokens: &mut Peekable<impl AsmTokenIter<'a>>) -> Result<()> {
+        let name_span = tokens.peek().map(|token| token.span).unwrap_or_default();
         let name = self
             .parse_identifier_argument(tokens)?
             .context("Expected identifier")?;
 
-        let (value, relocation, _) = self
+        if self.equs.iter().any(|equ| equ.name == name) {
+            return Err(error_at(name_span, "Symbol already defined"));
+        }
+
+        if should_return_none(tokens) {
+            bail!("Expected expression");
+        }
+        let (mut expr, span) = parse_spanned_expr(tokens)?;
+        expect_comma(tokens)?;
+
+        let section = self
+            .sections
+            .get_section()
+            .map_or(Self::NO_SECTION, |(section, _)| section);
+
+        let (value, type_, section) = match self.evaluate_expression(&expr, section).at(span)? {
+            ExprResult::Register(_) => return Err(error_at(span, "Invalid use of register")),
+            ExprResult::Constant {
+                constant,
+                section: None,
+                relocation: false,
+            } => (constant, Type::Constant, None),
+            ExprResult::Constant {
+                constant,
+                section: Some(section),
+                relocation: false,
+            } => (constant, Type::Label, Some(section)),
+            ExprResult::Constant {
+                relocation: true, ..
+            } => {
+                if self.symbols.get_symbol(&name).is_some() {
+                    return Err(error_at(name_span, "Symbol already defined"));
+                }
+
+                let mut location_counter = false;
+                expr.for_each_identifier(&mut |id| location_counter |= id == ".");
+                if location_counter {
+                    return Err(error_at(
+                        span,
+                        "Cannot use . in a .equ that refers to symbols defined later",
+                    ));
+                }
+                if self.equ_refers_to(&expr, &name) {
+                    return Err(error_at(
+                        span,
+                        format!("{name} is defined in terms of itself"),
+                    ));
+                }
+
+                expr.substitute(&|id| self.symbols.get_variable(id).map(Node::Constant));
+                self.equs.push(Equ {
+                    name,
+                    expr,
+                    section,
+                    span,
+                });
+                return Ok(());
+            }
+        };
+
+        self.symbols
+            .insert_symbol(name, value, type_, section)
+            .at(name_span)?;
+
+        Ok(())
+    }
+
+    /// Whether `expr` refers to `name`, either directly or through other `.equ`s that aren't
+    /// resolved yet
+    fn equ_refers_to(&self, expr: &Node, name: &str) -> bool {
+        let mut refers = false;
+        expr.for_each_identifier(&mut |id| {
+            refers |= id == name
+                || self
+                    .equs
+                    .iter()
+                    .find(|equ| equ.name == id)
+                    .is_some_and(|equ| self.equ_refers_to(&equ.expr, name));
+        });
+        refers
+    }
+
+    /// Parses `.set name, value`. Unlike `.equ` the symbol can be set again, and every use of it
+    /// sees the value it has at that point
+    fn parse_set<'a>(&mut self, tokens: &mut Peekable<impl AsmTokenIter<'a>>) -> Result<()> {
+        let name_span = tokens.peek().map(|token| token.span).unwrap_or_default();
+        let name = self
+            .parse_identifier_argument(tokens)?
+            .context("Expected identifier")?;
+
+        let (value, relocation, _, span) = self
             .parse_expr_argument(tokens)?
             .context("Expected expression")?;
 
         if relocation {
-            bail!("Constant cannot have a relocatable value");
+            return Err(error_at(
+                span,
+                "Value of .set must be a constant, but it isn't known yet",
+            ));
         }
 
-        self.symbols
-            .insert_symbol(name, value, Type::Constant, None)?;
+        self.symbols.set_variable(name, value).at(name_span)
+    }
+
+    fn parse_section_directive<'a>(
+        &mut self,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<()> {
+        let (name, flags, alignment) = self.parse_section_arguments(tokens)?;
+        self.sections.set_section(name.as_str(), flags)?;
+
+        let (_, section) = self.sections.get_section_mut()?;
+        section.alignment = section.alignment.max(alignment);
 
         Ok(())
     }
 
-    fn parse_section_directive<'a>(
+    /// Parses `.pushsection name`, which takes the same arguments as `.section` and saves the
+    /// current section so that `.popsection` can return to it
+    fn parse_pushsection<'a>(
         &mut self,
         tokens: &mut Peekable<impl AsmTokenIter<'a>>,
     ) -> Result<()> {
+        let span = tokens.peek().map(|token| token.span).unwrap_or_default();
+        let (name, flags, alignment) = self.parse_section_arguments(tokens)?;
+        self.sections.push_section(name.as_str(), flags)?;
+        self.pushed_sections.push((name, span));
+
+        let (_, section) = self.sections.get_section_mut()?;
+        section.alignment = section.alignment.max(alignment);
+
+        Ok(())
+    }
+
+    fn parse_popsection<'a>(
+        &mut self,
+        _tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<()> {
+        self.sections.pop_section()?;
+        self.pushed_sections.pop();
+        Ok(())
+    }
+
+    pub(super) fn check_section_stack(&mut self) -> Option<anyhow::Error> {
+        let (name, span) = self.pushed_sections.pop()?;
+        Some(error_at(
+            span,
+            format!(".pushsection {name} is missing its .popsection"),
+        ))
+    }
+
+    /// Parses the name, optional flags and optional alignment of `.section` and `.pushsection`
+    fn parse_section_arguments<'a>(
+        &mut self,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<(String, Option<SectionFlags>, u64)> {
         let section_name = self
             .parse_identifier_argument(tokens)?
             .with_context(|| "Expected identifier")?;
 
-        self.sections.set_section(section_name.as_str());
+        let flags = match self.parse_string_argument(tokens)? {
+            Some((flags, span)) => {
+                let flags = SectionFlags::parse_flags(&String::from_utf8_lossy(&flags)).at(span)?;
+                Some(flags)
+            }
+            None => None,
+        };
 
-        Ok(())
+        let alignment = match self.parse_expr_argument(tokens)? {
+            Some((_, true, _, span)) => {
+                return Err(error_at(span, "Section alignment must be a constant"));
+            }
+            Some((alignment, false, _, span)) if !alignment.is_power_of_two() => {
+                return Err(error_at(
+                    span,
+                    format!("Section alignment must be a power of two, but it is {alignment}"),
+                ));
+            }
+            Some((alignment, ..)) => alignment,
+            None => 1,
+        };
+
+        Ok((section_name, flags, alignment))
     }
 
+    /// Parses `.align n, fill, max_skip`. The fill byte can be left out like `.align 16,, 4`, and
+    /// the alignment is skipped if it needs more than `max_skip` bytes of padding
     fn parse_section_align<'a>(
         &mut self,
         tokens: &mut Peekable<impl AsmTokenIter<'a>>,
     ) -> Result<()> {
-        let (align, relocation, _) = self
+        let (align, relocation, _, span) = self
             .parse_expr_argument(tokens)?
             .context("Expected expression")?;
 
         if relocation {
-            bail!("Cannot align using a relocatable symbol");
+            return Err(error_at(span, "Cannot align using a relocatable symbol"));
         }
 
-        let (_, section) = self.sections.get_section_mut()?;
+        let fill = if let Some(AssemblerToken {
+            token: Token::Comma,
+            ..
+        }) = tokens.peek()
+        {
+            _ = tokens.next();
+            None
+        } else if let Some((fill, relocation, _, span)) = self.parse_expr_argument(tokens)? {
+            if relocation {
+                return Err(error_at(span, "Cannot relocate the fill value"));
+            }
+            let fill = u8::try_from(fill).map_err(|_| {
+                error_at(
+                    span,
+                    format!("The fill value must be a byte, but it is {fill}"),
+                )
+            })?;
+            Some(fill)
+        } else {
+            None
+        };
 
-        section.align(align);
+        let max_skip = match self.parse_expr_argument(tokens)? {
+            Some((_, true, _, span)) => {
+                return Err(error_at(span, "The maximum skip must be a constant"));
+            }
+            Some((max_skip, ..)) => Some(max_skip),
+            None => None,
+        };
 
-        Ok(())
+        let (_, section) = match fill {
+            Some(fill) if fill != 0 => self.sections.get_data_section_mut()?,
+            _ => self.sections.get_section_mut()?,
+        };
+
+        // Skipped padding doesn't align the cursor, so the section doesn't need the alignment
+        let count = section.padding(align).at(span)?;
+        if max_skip.is_some_and(|max_skip| count > max_skip) {
+            return Ok(());
+        }
+
+        section.alignment = section.alignment.max(align);
+        section.pad(count, fill).at(span)
     }
 
     fn parse_skip<'a>(&mut self, tokens: &mut Peekable<impl AsmTokenIter<'a>>) -> Result<()> {
-        let (skip_count, relocation, _) = self
+        let (skip_count, relocation, _, span) = self
             .parse_expr_argument(tokens)?
             .context("Expected expression")?;
 
         if relocation {
-            bail!("The skip count cannot be relocated");
+            return Err(error_at(span, "The skip count cannot be relocated"));
         }
 
         let fill_value =
-            if let Some((skip_count, relocation, _)) = self.parse_expr_argument(tokens)? {
+            if let Some((skip_count, relocation, _, span)) = self.parse_expr_argument(tokens)? {
                 if relocation {
-                    bail!("Cannot relocate the fill value");
+                    return Err(error_at(span, "Cannot relocate the fill value"));
                 }
                 skip_count as u8
             } else {
                 0
             };
 
-        let (_, section) = self.sections.get_section_mut()?;
-
-        for _ in 0..skip_count {
-            section.write_u8(fill_value);
+        // Zeroes can be reserved in nobits sections, anything else is initialized data
+        if fill_value == 0 {
+            let (_, section) = self.sections.get_section_mut()?;
+            section.reserve(skip_count);
+        } else {
+            let (_, section) = self.sections.get_data_section_mut()?;
+            for _ in 0..skip_count {
+                section.write_u8(fill_value);
+            }
         }
 
         Ok(())
@@ -346,4 +606,49 @@ impl Assembler {
 
         Ok(())
     }
+
+    /// Parses `.extern symbol, ...`, which declares symbols that are defined in another file
+    fn parse_extern<'a>(&mut self, tokens: &mut Peekable<impl AsmTokenIter<'a>>) -> Result<()> {
+        let mut count = 0usize;
+        loop {
+            let span = tokens.peek().map(|token| token.span).unwrap_or_default();
+            let Some(id) = self.parse_identifier_argument(tokens)? else {
+                break;
+            };
+            if id == "." {
+                return Err(error_at(span, "Cannot use . as a symbol"));
+            }
+            count += 1;
+            self.extern_symbols.push(id);
+        }
+
+        if count > 0 {
+            Ok(())
+        } else {
+            bail!("Expected one or more symbols")
+        }
+    }
+
+    /// Parses `.weak symbol, ...`. A weak definition can be overridden by a global one in another
+    /// module, and a weak reference that nothing defines is 0
+    fn parse_weak<'a>(&mut self, tokens: &mut Peekable<impl AsmTokenIter<'a>>) -> Result<()> {
+        let mut count = 0usize;
+        loop {
+            let span = tokens.peek().map(|token| token.span).unwrap_or_default();
+            let Some(id) = self.parse_identifier_argument(tokens)? else {
+                break;
+            };
+            if id == "." {
+                return Err(error_at(span, "Cannot use . as a symbol"));
+            }
+            count += 1;
+            self.weak_symbols.push(id);
+        }
+
+        if count > 0 {
+            Ok(())
+        } else {
+            bail!("Expected one or more symbols")
+        }
+    }
 }
@@ -1,6 +1,8 @@
 use crate::{
     assembler::{Assembler, ForwardReferenceEntry, Instruction},
-    bit, encoding,
+    bit,
+    diagnostic::{SpanContext, error_at},
+    encoding,
     opcode::{EncodingFlags, OperandFlags, Relocation},
     operand, section,
     tokens::Register,
@@ -216,8 +218,8 @@ impl Assembler {
     pub(super) fn emit_instruction(&mut self, mut instruction: Instruction) -> Result<usize> {
         let options = instruction.encoding.options;
 
-        let (section_id, section) = self.sections.get_section_mut()?;
-        let line_number = self.current_line;
+        let (section_id, section) = self.sections.get_data_section_mut()?;
+        let spans = instruction.spans;
 
         // Used for getting the current size of the instruction
         let start = section.cursor();
@@ -234,17 +236,16 @@ impl Assembler {
             // Maximum of two operands for any of these instructions
             debug_assert_eq!(instruction.operand_count, 2);
 
-            if instruction.types[1].intersects(OperandFlags::GP_REG) {
-                let dest = instruction.operands[0].register();
-                // let dest = GPRegister::try_from(dest)?;
+            let dest = instruction.operands[0].register();
+            let dest: ValidRegister = dest.try_into().at(spans[0])?;
 
+            if instruction.types[1].intersects(OperandFlags::GP_REG) {
                 let src = instruction.operands[1].register();
 
-                let transfer_byte = reg_transfer_byte(dest.try_into()?, src.try_into()?);
+                let transfer_byte = reg_transfer_byte(dest, src.try_into().at(spans[1])?);
                 section.write_u8(transfer_byte);
             } else if instruction.types[1].intersects(OperandFlags::IMM) {
                 // Two operands that are a register, and an immediate are garunteed
-                let dest = instruction.operands[0].register();
                 let src = instruction.operands[1].constant();
 
                 let constant_size = if instruction.reloc[1] {
@@ -260,9 +261,10 @@ impl Assembler {
                         section_id,
                         offset,
                         expr,
-                        self.current_line,
+                        spans[1],
                     );
-                    self.forward_references.push(entry);
+                    self.forward_references
+                        .push(entry.freeze_variables(&self.symbols));
                     Size::U64
                 } else {
                     if src <= u8::MAX.into() {
@@ -276,7 +278,7 @@ impl Assembler {
                     }
                 };
 
-                let transfer_byte = imm_transfer_byte(dest.try_into()?, constant_size);
+                let transfer_byte = imm_transfer_byte(dest, constant_size);
                 section.write_u8(transfer_byte);
 
                 match constant_size {
@@ -286,7 +288,6 @@ impl Assembler {
                     Size::U64 => section.write_u64(src),
                 }
             } else if instruction.types[1].intersects(OperandFlags::ADDR) {
-                let dest = instruction.operands[0].register();
                 let src = instruction.operands[1].constant();
 
                 if instruction.reloc[1] {
@@ -299,23 +300,23 @@ impl Assembler {
                         section_id,
                         offset,
                         expr,
-                        self.current_line,
+                        spans[1],
                     );
-                    self.forward_references.push(entry);
+                    self.forward_references
+                        .push(entry.freeze_variables(&self.symbols));
                 }
 
                 let size = get_memory_access_size(options);
 
-                let transfer_byte = const_addr_transfer_byte(dest.try_into()?, size);
+                let transfer_byte = const_addr_transfer_byte(dest, size);
 
                 section.write_u8(transfer_byte);
                 section.write_u64(src);
             } else if instruction.types[1].intersects(OperandFlags::DISP32) {
-                let dest = instruction.operands[0].register();
                 let disp = instruction.operands[1].constant();
 
                 let memory_access_size = get_memory_access_size(options);
-                let transfer_byte = disp_transfer_byte(dest.try_into()?, memory_access_size);
+                let transfer_byte = disp_transfer_byte(dest, memory_access_size);
 
                 section.write_u8(transfer_byte);
 
@@ -325,7 +326,7 @@ impl Assembler {
                     // i32 integer
                     let pc: u64 = (section.cursor() + size_of::<i32>()) as u64;
 
-                    let offset = calculate_disp32_offset(pc, disp)?;
+                    let offset = calculate_disp32_offset(pc, disp).at(spans[1])?;
 
                     debug!(
                         "Calculated offset {:#x} to {}+{:#x}",
@@ -344,15 +345,15 @@ impl Assembler {
                         section_id,
                         cursor,
                         expr,
-                        line_number,
+                        spans[1],
                     );
-                    self.forward_references.push(entry);
+                    self.forward_references
+                        .push(entry.freeze_variables(&self.symbols));
                     0
                 };
 
                 section.write_u32(offset as u32);
             } else if instruction.types[1].intersects(OperandFlags::INDEX) {
-                let dest = instruction.operands[0].register();
                 let mut memory_index = instruction.indexes[1];
                 let size = get_memory_access_size(options);
 
@@ -380,7 +381,9 @@ impl Assembler {
                     2 => 1,
                     4 => 2,
                     8 => 3,
-                    _ if memory_index.index.is_valid() => return Err(anyhow!("Invalid scale")),
+                    _ if memory_index.index.is_valid() => {
+                        return Err(error_at(spans[1], "Invalid scale"));
+                    }
                     _ => 0,
                 };
 
@@ -388,7 +391,7 @@ impl Assembler {
 
                 // Stack pointer based addressing.
                 if memory_index.base.is_sp() {
-                    let trsnfr = sp_rel_transfer_byte(dest.try_into()?, size);
+                    let trsnfr = sp_rel_transfer_byte(dest, size);
                     section.write_u8(trsnfr);
 
                     // We don't write this byte right after this statement because the 4byte/2byte
@@ -400,8 +403,9 @@ impl Assembler {
                         byte
                     } else {
                         if memory_index.index.is_valid() && !memory_index.index.is_gp() {
-                            return Err(anyhow!(
-                                "Index register must be a general purpose register"
+                            return Err(error_at(
+                                spans[1],
+                                "Index register must be a general purpose register",
                             ));
                         }
                         let byte = memory_index_byte(Register::none(), scale, false, true);
@@ -428,16 +432,17 @@ impl Assembler {
                                 section_id,
                                 offset,
                                 expr.expect("Expression should be some"),
-                                line_number,
+                                spans[1],
                             );
-                            self.forward_references.push(entry);
+                            self.forward_references
+                                .push(entry.freeze_variables(&self.symbols));
                         }
                         section.write_u32(disp as u32);
                     } else {
-                        return Err(anyhow!("Displacement out of range"));
+                        return Err(error_at(spans[1], "Displacement out of range"));
                     }
                 } else if memory_index.base.is_valid() {
-                    let byte = base_index_transfer_byte(dest.try_into()?, size);
+                    let byte = base_index_transfer_byte(dest, size);
                     section.write_u8(byte);
 
                     let mut bis_byte = if memory_index.index.is_valid() {
@@ -445,13 +450,14 @@ impl Assembler {
                             let byte = memory_index_byte(Register::none(), scale, false, true);
                             byte
                         } else {
-                            return Err(anyhow!(
-                                "Index register must be a general purpose register"
+                            return Err(error_at(
+                                spans[1],
+                                "Index register must be a general purpose register",
                             ));
                         }
                     } else {
                         if !memory_index.base.is_gp() {
-                            return Err(anyhow!("Invalid base register"));
+                            return Err(error_at(spans[1], "Invalid base register"));
                         }
                         let byte = memory_index_byte(memory_index.base, scale, false, false);
                         byte
@@ -491,14 +497,15 @@ impl Assembler {
                                 section_id,
                                 offset,
                                 expr.expect("Expression should be some"),
-                                line_number,
+                                spans[1],
                             );
-                            self.forward_references.push(entry);
+                            self.forward_references
+                                .push(entry.freeze_variables(&self.symbols));
                         }
 
                         section.write_u32(disp as u32);
                     } else {
-                        return Err(anyhow!("Displacement out of range"));
+                        return Err(error_at(spans[1], "Displacement out of range"));
                     }
                 } else if memory_index.base.is_invalid() && memory_index.index.is_invalid() {
                     todo!("Constant addressing")
@@ -512,7 +519,8 @@ impl Assembler {
                     let byte: u8 = instruction.operands[0]
                         .constant()
                         .try_into()
-                        .context("Constant too large to fit in one byte")?;
+                        .context("Constant too large to fit in one byte")
+                        .at(spans[0])?;
 
                     if instruction.reloc[0] {
                         let offset = section.cursor();
@@ -522,9 +530,10 @@ impl Assembler {
                             section_id,
                             offset,
                             expr.expect("Expression should be some"),
-                            line_number,
+                            spans[0],
                         );
-                        self.forward_references.push(entry);
+                        self.forward_references
+                            .push(entry.freeze_variables(&self.symbols));
                     }
 
                     section.write_u8(byte);
@@ -537,7 +546,7 @@ impl Assembler {
             let offset = if !instruction.reloc[0] {
                 // Where the program counter will be when this instruction is executed
                 let pc: u64 = (section.cursor() + 4).try_into().unwrap();
-                let offset = calculate_disp32_offset(pc, disp)?;
+                let offset = calculate_disp32_offset(pc, disp).at(spans[0])?;
                 debug!(
                     "Calculated offset {:#x} to {}+{:#x}",
                     offset,
@@ -555,16 +564,19 @@ impl Assembler {
                     section_id,
                     cursor,
                     expr,
-                    line_number,
+                    spans[0],
                 );
-                self.forward_references.push(entry);
+                self.forward_references
+                    .push(entry.freeze_variables(&self.symbols));
                 0
             };
 
             section.write_u32(offset as u32);
         } else if options.intersects(encoding!(OPCODE_REG)) {
             let reg = instruction.operands[0].register();
-            let reg = ValidRegister::try_from(reg).context("Invalid register")?;
+            let reg = ValidRegister::try_from(reg)
+                .context("Invalid register")
+                .at(spans[0])?;
 
             // Instructions with the OPCODE_REG option has its register encoded as the last 4 bits
             let cursor = section.cursor();
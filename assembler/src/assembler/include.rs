@@ -0,0 +1,281 @@
+use std::{
+    fs,
+    iter::Peekable,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    assembler::{AsmTokenIter, Assembler, Line},
+    diagnostic::{Span, SpanContext, error_at},
+};
+
+/// A file spliced into the source code with `.include`
+#[derive(Debug)]
+pub struct IncludedFile {
+    /// The path of the file as it is shown in diagnostics
+    pub name: String,
+    path: PathBuf,
+    /// Used to detect include cycles. `None` if the path couldn't be canonicalized
+    canonical: Option<PathBuf>,
+    pub source: String,
+    /// The file name argument of the `.include` that included this file
+    pub include_span: Span,
+}
+
+impl Assembler {
+    /// Parses `.include "file"`. The tokens of the file are parsed right after the current line
+    pub(super) fn parse_include<'a>(
+        &mut self,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<()> {
+        let (name, span) = self.parse_file_argument(tokens)?;
+        let path = self.resolve_file(&name, span.file).at(span)?;
+        let canonical = fs::canonicalize(&path).ok();
+
+        // Walk up the files that included this one to make sure the new file isn't one of them
+        let mut chain = vec![path.display().to_string()];
+        let mut file = span.file;
+        loop {
+            chain.push(self.file(file).0.to_string());
+            if canonical.is_some() && self.canonical_path(file) == canonical {
+                chain.reverse();
+                return Err(error_at(
+                    span,
+                    format!("Include cycle: {}", chain.join(" -> ")),
+                ));
+            }
+
+            match file.checked_sub(1) {
+                Some(i) => file = self.included[i].include_span.file,
+                None => break,
+            }
+        }
+
+        let source = fs::read_to_string(&path)
+            .map_err(|e| error_at(span, format!("Cannot read {}: {e}", path.display())))?;
+
+        self.included.push(IncludedFile {
+            name: path.display().to_string(),
+            path,
+            canonical,
+            source,
+            include_span: span,
+        });
+
+        let id = self.included.len();
+        let tokens = Self::tokenize_file(&self.included[id - 1].source, id)?;
+
+        // `self.lines` is a stack so the first line of the file has to go on top
+        self.lines.extend(Line::split(tokens).into_iter().rev());
+
+        Ok(())
+    }
+
+    /// Parses `.incbin "file", offset, length`, which copies `length` bytes of the file starting
+    /// at `offset` into the current section. Both are optional and default to the entire file
+    pub(super) fn parse_incbin<'a>(
+        &mut self,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<()> {
+        let (name, span) = self.parse_file_argument(tokens)?;
+        let path = self.resolve_file(&name, span.file).at(span)?;
+        let bytes = fs::read(&path)
+            .map_err(|e| error_at(span, format!("Cannot read {}: {e}", path.display())))?;
+        let size = bytes.len() as u64;
+
+        let mut offset = 0;
+        if let Some((value, relocation, _, offset_span)) = self.parse_expr_argument(tokens)? {
+            if relocation {
+                return Err(error_at(offset_span, "Offset must be a constant"));
+            }
+            if value > size {
+                return Err(error_at(
+                    offset_span,
+                    format!("Offset {value} is past the end of {name} which is {size} bytes"),
+                ));
+            }
+            offset = value;
+        }
+
+        let mut length = size - offset;
+        if let Some((value, relocation, _, length_span)) = self.parse_expr_argument(tokens)? {
+            if relocation {
+                return Err(error_at(length_span, "Length must be a constant"));
+            }
+            if value > length {
+                return Err(error_at(
+                    length_span,
+                    format!(
+                        "Cannot read {value} bytes at offset {offset} of {name} which is {size} bytes"
+                    ),
+                ));
+            }
+            length = value;
+        }
+
+        let (_, section) = self.sections.get_data_section_mut()?;
+        section.write_bytes(&bytes[offset as usize..(offset + length) as usize]);
+
+        Ok(())
+    }
+
+    fn parse_file_argument<'a>(
+        &self,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<(String, Span)> {
+        let (name, span) = self
+            .parse_string_argument(tokens)?
+            .context("Expected a file name")?;
+        let name = String::from_utf8(name.to_vec())
+            .map_err(|_| error_at(span, "File name must be valid UTF-8"))?;
+
+        Ok((name, span))
+    }
+
+    /// Finds `name` relative to the directory of the file with the id `from`, or otherwise in
+    /// one of the include paths
+    fn resolve_file(&self, name: &str, from: usize) -> Result<PathBuf> {
+        let path = Path::new(name);
+        if path.is_absolute() {
+            return match path.is_file() {
+                true => Ok(path.to_path_buf()),
+                false => Err(anyhow::anyhow!("Cannot find {name}")),
+            };
+        }
+
+        let directory = match from.checked_sub(1) {
+            Some(i) => self.included[i].path.parent(),
+            None => Path::new(&self.filename).parent(),
+        }
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+
+        std::iter::once(&directory)
+            .chain(self.include_paths.iter())
+            .map(|directory| directory.join(path))
+            .find(|path| path.is_file())
+            .with_context(|| {
+                format!(
+                    "Cannot find {name} in the directory of {} or any include path",
+                    self.file(from).0
+                )
+            })
+    }
+
+    fn canonical_path(&self, file: usize) -> Option<PathBuf> {
+        match file.checked_sub(1) {
+            Some(i) => self.included[i].canonical.clone(),
+            None => fs::canonicalize(&self.filename).ok(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::tests::format_first_error;
+
+    /// Creates an empty directory for a test to write its files into
+    fn test_directory(test: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("include-{test}-{}", std::process::id()));
+        _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).expect("Should be able to create a temporary directory");
+        directory
+    }
+
+    /// Assembles `directory/main.asm` and returns the bytes of its first section or the first
+    /// formatted error
+    fn assemble(
+        directory: &Path,
+        source: &str,
+        include_paths: Vec<PathBuf>,
+    ) -> std::result::Result<Vec<u8>, String> {
+        let filename = directory.join("main.asm").display().to_string();
+        let mut assembler =
+            Assembler::assemble_with_include_paths(filename, String::new(), include_paths)
+                .expect("Empty source should assemble");
+
+        match format_first_error(&mut assembler, source) {
+            Some(error) => Err(error),
+            None => Ok(assembler.sections[0].data.get_ref().clone()),
+        }
+    }
+
+    #[test]
+    fn test_include() {
+        let directory = test_directory("include");
+        let shared = directory.join("shared");
+        fs::create_dir_all(&shared).unwrap();
+        fs::write(
+            directory.join("constants.inc"),
+            ".equ A, 1\n.include \"nested.inc\"\n",
+        )
+        .unwrap();
+        fs::write(directory.join("nested.inc"), ".equ B, 2").unwrap();
+        fs::write(shared.join("macros.inc"), ".macro byte v\n.u8 \\v\n.endm\n").unwrap();
+
+        let source =
+            ".include \"constants.inc\"\n.include \"macros.inc\"\n.section .entry\nbyte A\nbyte B";
+        assert_eq!(
+            assemble(&directory, source, vec![shared.clone()]).unwrap(),
+            &[1, 2]
+        );
+
+        // Without the include path the macros can't be found
+        let error = assemble(&directory, source, Vec::new()).unwrap_err();
+        assert!(error.contains("Cannot find macros.inc"));
+        assert!(error.contains(":2:10"));
+    }
+
+    #[test]
+    fn test_include_diagnostics() {
+        let directory = test_directory("diagnostics");
+        fs::write(directory.join("bad.inc"), ".section .entry\n.u8 1 2\n").unwrap();
+        fs::write(directory.join("a.inc"), ".include \"b.inc\"\n").unwrap();
+        fs::write(directory.join("b.inc"), "\n.include \"a.inc\"\n").unwrap();
+
+        let error = assemble(&directory, "\n.include \"bad.inc\"", Vec::new()).unwrap_err();
+        let bad = directory.join("bad.inc").display().to_string();
+        let main = directory.join("main.asm").display().to_string();
+        assert!(error.contains(&format!(" --> {bad}:2:7\n")));
+        assert!(error.contains("2 | .u8 1 2"));
+        assert!(error.contains(&format!(
+            "note: in file included from {main}\n --> {main}:2:10"
+        )));
+
+        let error = assemble(&directory, ".include \"a.inc\"", Vec::new()).unwrap_err();
+        assert!(error.contains("Include cycle"));
+        assert!(error.contains("b.inc:2:10"));
+    }
+
+    #[test]
+    fn test_incbin() {
+        let directory = test_directory("incbin");
+        fs::write(directory.join("data.bin"), [1, 2, 3, 4, 5]).unwrap();
+
+        let source = ".section .entry\n.incbin \"data.bin\"\n.incbin \"data.bin\", 3\n.incbin \"data.bin\", 1, 2";
+        assert_eq!(
+            assemble(&directory, source, Vec::new()).unwrap(),
+            &[1, 2, 3, 4, 5, 4, 5, 2, 3]
+        );
+
+        let error = assemble(
+            &directory,
+            ".section .entry\n.incbin \"data.bin\", 6",
+            Vec::new(),
+        );
+        assert!(error.unwrap_err().contains("past the end"));
+        let error = assemble(
+            &directory,
+            ".section .entry\n.incbin \"data.bin\", 2, 4",
+            Vec::new(),
+        );
+        assert!(
+            error
+                .unwrap_err()
+                .contains("Cannot read 4 bytes at offset 2")
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use std::borrow::Cow;
+
+use anyhow::Result;
+
+use crate::{
+    assembler::{Assembler, AssemblerToken},
+    diagnostic::{Span, error_at},
+    expression::Builtin,
+    tokens::{Direction, Directive, Token},
+};
+
+/// A forward reference to a numeric local label, which must be defined by the end of the source
+#[derive(Debug)]
+pub struct LocalLabelReference {
+    label: u64,
+    /// The definition the reference resolved to
+    index: usize,
+    span: Span,
+}
+
+/// The name of the symbol a definition of a numeric local label is stored as. Identifiers can't
+/// contain `@`, so it can't clash with any other symbol
+fn symbol_name(label: u64, index: usize) -> String {
+    format!("{label}@{index}")
+}
+
+impl Assembler {
+    /// Replaces local labels in `tokens` with the symbols they stand for.
+    ///
+    /// - Numeric labels like `1:` get a new symbol every time they are defined, `1b` refers to
+    ///   the latest one and `1f` to the next one
+    /// - Labels starting with a dot are scoped to the last label without one, so `.loop` after
+    ///   `memcpy:` is `memcpy.loop`. Section names, like the ones of `.section` and
+    ///   `sizeof(.data)`, are left alone
+    ///
+    /// Since a definition is only allowed at the start of a statement, `tokens` must be the
+    /// entire line
+    pub(super) fn resolve_local_labels<'a>(
+        &mut self,
+        tokens: &'a [AssemblerToken],
+    ) -> Result<Cow<'a, [AssemblerToken]>> {
+        let mut resolved = Cow::Borrowed(tokens);
+        let mut statement_start = true;
+        let mut label_colon = false;
+
+        for (i, token) in tokens.iter().enumerate() {
+            // Another statement can follow the colon of a label
+            if std::mem::take(&mut label_colon) {
+                statement_start = true;
+                continue;
+            }
+
+            let is_definition = std::mem::take(&mut statement_start)
+                && matches!(
+                    tokens.get(i + 1),
+                    Some(AssemblerToken {
+                        token: Token::Colon,
+                        ..
+                    })
+                );
+            label_colon = is_definition;
+
+            let is_section_name = match &tokens[..i] {
+                [
+                    ..,
+                    AssemblerToken {
+                        token: Token::Directive(Directive::Section | Directive::Pushsection),
+                        ..
+                    },
+                ] => true,
+                [
+                    ..,
+                    AssemblerToken {
+                        token: Token::Identifier(function),
+                        ..
+                    },
+                    AssemblerToken {
+                        token: Token::LBrace,
+                        ..
+                    },
+                ] => Builtin::from_name(function)
+                    .is_some_and(|function| function.takes_section_name()),
+                _ => false,
+            };
+
+            let name = match &token.token {
+                // Fields of a struct are always scoped to the struct
+                Token::Identifier(name)
+                    if is_definition && !name.contains('.') && self.open_struct.is_none() =>
+                {
+                    self.label_scope = Some(name.clone());
+                    continue;
+                }
+                Token::Identifier(name)
+                    if name.len() > 1 && name.starts_with('.') && !is_section_name =>
+                {
+                    match &self.label_scope {
+                        Some(scope) => format!("{scope}{name}"),
+                        None => continue,
+                    }
+                }
+                &Token::Number(label) if is_definition => {
+                    let index = self.local_labels.entry(label).or_default();
+                    *index += 1;
+                    symbol_name(label, *index - 1)
+                }
+                &Token::LocalLabel(label, Direction::Backward) => {
+                    let index = self
+                        .local_labels
+                        .get(&label)
+                        .and_then(|defined| defined.checked_sub(1))
+                        .ok_or_else(|| {
+                            error_at(
+                                token.span,
+                                format!("Local label {label} isn't defined before {label}b"),
+                            )
+                        })?;
+                    symbol_name(label, index)
+                }
+                &Token::LocalLabel(label, Direction::Forward) => {
+                    let index = self.local_labels.get(&label).copied().unwrap_or_default();
+                    self.local_label_references.push(LocalLabelReference {
+                        label,
+                        index,
+                        span: token.span,
+                    });
+                    symbol_name(label, index)
+                }
+                _ => continue,
+            };
+
+            resolved.to_mut()[i].token = Token::Identifier(name);
+        }
+
+        Ok(resolved)
+    }
+
+    /// Reports every `1f` that has no definition after it
+    pub(super) fn check_local_label_references(&mut self) -> Vec<anyhow::Error> {
+        self.local_label_references
+            .drain(..)
+            .filter(|reference| {
+                self.local_labels
+                    .get(&reference.label)
+                    .is_none_or(|&defined| defined <= reference.index)
+            })
+            .map(|reference| {
+                let label = reference.label;
+                error_at(
+                    reference.span,
+                    format!("Local label {label} isn't defined after {label}f"),
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::tests::{build, first_error_message};
+
+    #[test]
+    fn test_jumps() {
+        let numeric = build(
+            "
+            .section .entry
+            1: add r0, 1
+            jnz 1b
+            jmp 1f
+            halt
+            1: jz 1b
+            2: 3: jmp 2f
+            jmp 3b
+            2:
+            ",
+        )
+        .expect("Module should build");
+        let named = build(
+            "
+            .section .entry
+            a: add r0, 1
+            jnz a
+            jmp b
+            halt
+            b: jz b
+            c: d: jmp e
+            jmp d
+            e:
+            ",
+        )
+        .expect("Module should build");
+
+        assert_eq!(
+            numeric.sections[0].data.get_ref(),
+            named.sections[0].data.get_ref()
+        );
+        assert_eq!(numeric.relocations.len(), named.relocations.len());
+    }
+
+    #[test]
+    fn test_data() {
+        // `0b` is a label and not an empty binary number
+        let module = build(".section .entry\n1: .u8 1f - 1b, 0\n1: .u8 0f - 1b\n0: .u8 0b - 1b")
+            .expect("Module should build");
+        assert_eq!(module.sections[0].data.get_ref(), &[2, 0, 1, 1]);
+
+        // Every expansion of a macro gets its own labels
+        let source = "
+        .section .entry
+        .macro byte value
+        1: .u8 \\value
+        .u8 1b - start
+        .endm
+        start:
+        byte 7
+        byte 8
+        ";
+        assert_eq!(
+            build(source).expect("Module should build").sections[0]
+                .data
+                .get_ref(),
+            &[7, 0, 8, 2]
+        );
+    }
+
+    #[test]
+    fn test_scoped() {
+        let source = "
+        .section .entry
+        memcpy:
+        .loop: .u8 .loop - memcpy, .done - memcpy
+        .done: .u8 memset.loop - memcpy
+        memset: .loop:
+        .u8 .loop - memcpy, memcpy.done - memcpy
+        ";
+        let module = build(source).expect("Module should build");
+        assert!(module.relocations.is_empty());
+        assert_eq!(module.sections[0].data.get_ref(), &[0, 2, 3, 3, 2]);
+        assert!(module.symbols.get_symbol("memcpy.loop").is_some());
+        assert!(module.symbols.get_symbol("memset.loop").is_some());
+
+        // Section names aren't labels
+        let module = build(".section .entry\nstart:\n.section .data\n.u64 .end\n.end:")
+            .expect("Module should build");
+        assert_eq!(module.relocations[0].symbol, "start.end");
+
+        let source = "
+        .section .data
+        table: .u8 1, 2, 3
+        .align 4
+        .section .entry
+        start:
+        .u8 sizeof(.data), alignof(.data), .end - start, defined(.end)
+        .end:
+        ";
+        let module = build(source).expect("Module should build");
+        assert_eq!(module.sections[".entry"].data.get_ref(), &[4, 4, 4, 0]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = first_error_message(".section .entry\n1:\njmp 2b");
+        assert!(error.contains("Local label 2 isn't defined before 2b"));
+        assert!(error.contains(" --> test.asm:3:5"));
+
+        let error = first_error_message(".section .entry\n1:\njmp 1f");
+        assert!(error.contains("Local label 1 isn't defined after 1f"));
+        assert!(error.contains(" --> test.asm:3:5"));
+    }
+}
@@ -0,0 +1,522 @@
+use std::{iter::Peekable, rc::Rc};
+
+use anyhow::Result;
+
+use crate::{
+    assembler::{AsmTokenIter, Assembler, AssemblerToken, Line, repeat::repetition_symbol},
+    diagnostic::{Span, SpanContext, error_at},
+    tokens::{Directive, Token},
+};
+
+/// How deep macros can invoke other macros before we assume they recurse forever
+const RECURSION_LIMIT: usize = 64;
+
+#[derive(Debug)]
+pub struct Macro {
+    name: String,
+    params: Vec<Parameter>,
+    /// The tokens of every line between `.macro` and `.endm`
+    body: Vec<Vec<AssemblerToken>>,
+}
+
+#[derive(Debug)]
+struct Parameter {
+    name: String,
+    /// Used when the argument is left out or empty
+    default: Vec<AssemblerToken>,
+    /// Collects every remaining argument including the commas between them. Only the last
+    /// parameter can be variadic
+    vararg: bool,
+}
+
+/// A single invocation of a macro
+#[derive(Debug)]
+pub struct Expansion {
+    pub name: String,
+    /// Where the macro was invoked
+    pub call_span: Span,
+    /// The expansion the invocation itself came from
+    pub parent: Option<Rc<Expansion>>,
+}
+
+impl Expansion {
+    /// The number of macro invocations this expansion is nested in, including itself
+    fn depth(&self) -> usize {
+        1 + self.parent.as_ref().map_or(0, |parent| parent.depth())
+    }
+}
+
+/// Calls `f` with the name of every parameter referenced in `text` in the form of `\name`. `\@`
+/// is not a parameter and is skipped
+fn parameter_references(text: &str, mut f: impl FnMut(&str) -> Result<()>) -> Result<()> {
+    let mut rest = text;
+    while let Some(i) = rest.find('\\') {
+        rest = &rest[i + 1..];
+        if let Some(after) = rest.strip_prefix('@') {
+            rest = after;
+            continue;
+        }
+
+        let len = rest
+            .find(|ch: char| !(ch.is_alphanumeric() || ch == '_'))
+            .unwrap_or(rest.len());
+        let (name, after) = rest.split_at(len);
+        f(name)?;
+        rest = after;
+    }
+
+    Ok(())
+}
+
+impl Macro {
+    fn parameter(&self, name: &str) -> Option<usize> {
+        self.params.iter().position(|param| param.name == name)
+    }
+}
+
+/// Replaces the parameters referenced by `token` with the arguments in `args` and pushes the
+/// result onto `out`. `parameter` looks up the index of a parameter in `args` by its name, and
+/// `\@` is replaced with `unique` unless it is `None`.
+///
+/// A token that is only a parameter is replaced with the argument's tokens as is. Otherwise the
+/// arguments are pasted into the identifier's text, which is then tokenized again
+pub(super) fn substitute(
+    token: &AssemblerToken,
+    parameter: impl Fn(&str) -> Option<usize>,
+    args: &[Vec<AssemblerToken>],
+    unique: Option<usize>,
+    out: &mut Vec<AssemblerToken>,
+) -> Result<()> {
+    let Token::Identifier(text) = &token.token else {
+        out.push(token.clone());
+        return Ok(());
+    };
+    if !text.contains('\\') {
+        out.push(token.clone());
+        return Ok(());
+    }
+
+    if let Some(name) = text.strip_prefix('\\')
+        && let Some(index) = parameter(name)
+    {
+        out.extend(args[index].iter().cloned());
+        return Ok(());
+    }
+
+    let mut pasted = String::new();
+    let mut rest = text.as_str();
+    while let Some(i) = rest.find('\\') {
+        pasted.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+
+        if let Some(after) = rest.strip_prefix('@') {
+            match unique {
+                Some(unique) => pasted.push_str(&unique.to_string()),
+                None => pasted.push_str("\\@"),
+            }
+            rest = after;
+            continue;
+        }
+
+        let len = rest
+            .find(|ch: char| !(ch.is_alphanumeric() || ch == '_'))
+            .unwrap_or(rest.len());
+        let (name, after) = rest.split_at(len);
+        rest = after;
+
+        // Parameters of a nested macro definition or repetition are left alone until that is
+        // expanded
+        let Some(index) = parameter(name) else {
+            pasted.push('\\');
+            pasted.push_str(name);
+            continue;
+        };
+
+        for arg in args[index].iter() {
+            if let Token::Ascii(_) = arg.token {
+                return Err(error_at(
+                    arg.span,
+                    format!("Cannot paste a string into `{text}`"),
+                ));
+            }
+            pasted.push_str(&arg.token.to_string());
+        }
+    }
+    pasted.push_str(rest);
+
+    if pasted == *text {
+        out.push(token.clone());
+        return Ok(());
+    }
+
+    let tokens = Assembler::tokenize(&pasted).at(token.span)?;
+    out.extend(tokens.into_iter().map(|pasted| AssemblerToken {
+        token: pasted.token,
+        span: token.span,
+    }));
+
+    Ok(())
+}
+
+/// Splits the rest of the line into arguments on commas that aren't inside of braces. Returns
+/// the arguments and the commas between them
+pub(super) fn split_arguments<'a>(
+    tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+) -> (Vec<Vec<AssemblerToken>>, Vec<AssemblerToken>) {
+    let mut args: Vec<Vec<AssemblerToken>> = Vec::new();
+    let mut commas = Vec::new();
+    let mut current = Vec::new();
+    let mut nesting = 0usize;
+    for token in tokens.by_ref() {
+        match token.token {
+            Token::Newline => break,
+            Token::LBrace | Token::LSqrBrace => nesting += 1,
+            Token::RBrace | Token::RSqrBrace => nesting = nesting.saturating_sub(1),
+            Token::Comma if nesting == 0 => {
+                args.push(std::mem::take(&mut current));
+                commas.push(token.clone());
+                continue;
+            }
+            _ => {}
+        }
+        current.push(token.clone());
+    }
+    if !current.is_empty() || !args.is_empty() {
+        args.push(current);
+    }
+
+    (args, commas)
+}
+
+impl Assembler {
+    /// Parses `.macro name param, param=default, rest:vararg` and every line up to the matching
+    /// `.endm`. `span` is the span of the `.macro` directive
+    pub(super) fn parse_macro_definition<'a>(
+        &mut self,
+        span: Span,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<()> {
+        let name_token = tokens
+            .next()
+            .filter(|token| !matches!(token.token, Token::Newline))
+            .ok_or_else(|| error_at(span, "Expected macro name"))?;
+        let Token::Identifier(name) = &name_token.token else {
+            return Err(error_at(
+                name_token.span,
+                format!("Cannot use {} as a macro name", name_token.token),
+            ));
+        };
+
+        let mut params: Vec<Parameter> = Vec::new();
+        while let Some(token) = tokens.next() {
+            let param_name = match &token.token {
+                Token::Newline => break,
+                Token::Identifier(param) => param,
+                other => {
+                    return Err(error_at(
+                        token.span,
+                        format!("Expected parameter name but got {other}"),
+                    ));
+                }
+            };
+
+            if params.last().is_some_and(|param| param.vararg) {
+                return Err(error_at(
+                    token.span,
+                    "Only the last parameter can be variadic",
+                ));
+            }
+            if params.iter().any(|param| param.name == *param_name) {
+                return Err(error_at(
+                    token.span,
+                    format!("Duplicate parameter {param_name}"),
+                ));
+            }
+
+            let mut param = Parameter {
+                name: param_name.clone(),
+                default: Vec::new(),
+                vararg: false,
+            };
+
+            match tokens.peek().map(|token| &token.token) {
+                Some(Token::Colon) => {
+                    _ = tokens.next();
+                    match tokens.next() {
+                        Some(AssemblerToken {
+                            token: Token::Identifier(qualifier),
+                            ..
+                        }) if qualifier == "vararg" => param.vararg = true,
+                        Some(token) => {
+                            return Err(error_at(
+                                token.span,
+                                "Expected `vararg` after the parameter name",
+                            ));
+                        }
+                        None => {
+                            return Err(error_at(
+                                token.span,
+                                "Expected `vararg` after the parameter name",
+                            ));
+                        }
+                    }
+                }
+                Some(Token::Equal) => {
+                    _ = tokens.next();
+                    while let Some(token) = tokens.peek()
+                        && !matches!(token.token, Token::Comma | Token::Newline)
+                    {
+                        param.default.push((*token).clone());
+                        _ = tokens.next();
+                    }
+                }
+                _ => {}
+            }
+            params.push(param);
+
+            match tokens.next() {
+                None
+                | Some(AssemblerToken {
+                    token: Token::Newline,
+                    ..
+                }) => break,
+                Some(AssemblerToken {
+                    token: Token::Comma,
+                    ..
+                }) => {}
+                Some(token) => return Err(error_at(token.span, "Expected comma")),
+            }
+        }
+
+        // Collect the body. Nested definitions are kept in the body and defined when the macro
+        // is expanded
+        let mut body = Vec::new();
+        let mut depth = 0usize;
+        // Parameters are only checked outside of nested definitions since those have their own
+        let mut checked = Vec::new();
+        loop {
+            let Some(line) = self.lines.pop() else {
+                return Err(error_at(span, format!("Macro {name} is missing its .endm")));
+            };
+
+            let check = depth == 0;
+
+            match line.tokens.first().map(|token| &token.token) {
+                Some(Token::Directive(Directive::Macro)) => depth += 1,
+                Some(Token::Directive(Directive::Endm)) if depth == 0 => {
+                    if let Some(token) = line.tokens.get(1)
+                        && !matches!(token.token, Token::Newline)
+                    {
+                        return Err(error_at(token.span, "Unexpected token"));
+                    }
+                    break;
+                }
+                Some(Token::Directive(Directive::Endm)) => depth -= 1,
+                _ => {}
+            }
+
+            if check {
+                checked.push(body.len());
+            }
+            body.push(line.tokens);
+        }
+
+        let macro_ = Macro {
+            name: name.clone(),
+            params,
+            body,
+        };
+
+        // Catch misspelled parameters now instead of every time the macro is used. Repetitions
+        // inside of the macro can reference their own symbols as well
+        let symbols: Vec<&str> = checked
+            .iter()
+            .filter_map(|&i| repetition_symbol(&macro_.body[i]))
+            .collect();
+        for token in checked.iter().flat_map(|&i| macro_.body[i].iter()) {
+            if let Token::Identifier(text) = &token.token {
+                parameter_references(text, |param| match macro_.parameter(param) {
+                    Some(_) => Ok(()),
+                    None if symbols.contains(&param) => Ok(()),
+                    None => Err(error_at(
+                        token.span,
+                        format!("Macro {name} has no parameter named {param}"),
+                    )),
+                })?;
+            }
+        }
+
+        if self.macros.contains_key(name) {
+            return Err(error_at(
+                name_token.span,
+                format!("Macro {name} is already defined"),
+            ));
+        }
+        self.macros.insert(name.clone(), Rc::new(macro_));
+
+        Ok(())
+    }
+
+    /// Expands an invocation of `macro_` whose arguments are the rest of `tokens`. The expanded
+    /// lines are parsed next
+    pub(super) fn expand_macro<'a>(
+        &mut self,
+        macro_: &Macro,
+        call_span: Span,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+        line: &Line,
+    ) -> Result<()> {
+        let depth = line.expansion.as_ref().map_or(0, |parent| parent.depth());
+        if depth >= RECURSION_LIMIT {
+            return Err(error_at(
+                call_span,
+                format!(
+                    "Macro {} exceeded the recursion limit of {RECURSION_LIMIT}",
+                    macro_.name
+                ),
+            ));
+        }
+
+        let (args, commas) = split_arguments(tokens);
+
+        let variadic = macro_.params.last().is_some_and(|param| param.vararg);
+        if args.len() > macro_.params.len() && !variadic {
+            let span = args[macro_.params.len()]
+                .first()
+                .map(|token| token.span)
+                .unwrap_or(call_span);
+            return Err(error_at(
+                span,
+                format!(
+                    "Macro {} takes {} arguments but {} were given",
+                    macro_.name,
+                    macro_.params.len(),
+                    args.len()
+                ),
+            ));
+        }
+
+        let mut bound = Vec::with_capacity(macro_.params.len());
+        for (i, param) in macro_.params.iter().enumerate() {
+            let mut value = Vec::new();
+            if param.vararg {
+                for (j, arg) in args.iter().enumerate().skip(i) {
+                    if j > i {
+                        value.push(commas[j - 1].clone());
+                    }
+                    value.extend(arg.iter().cloned());
+                }
+            } else if let Some(arg) = args.get(i) {
+                value = arg.clone();
+            }
+
+            if value.is_empty() {
+                value = param.default.clone();
+            }
+            bound.push(value);
+        }
+
+        let unique = self.macro_count;
+        self.macro_count += 1;
+
+        let expansion = Rc::new(Expansion {
+            name: macro_.name.clone(),
+            call_span,
+            parent: line.expansion.clone(),
+        });
+
+        let mut lines = Vec::with_capacity(macro_.body.len());
+        for body_line in macro_.body.iter() {
+            let mut tokens = Vec::with_capacity(body_line.len());
+            for token in body_line {
+                substitute(
+                    token,
+                    |name| macro_.parameter(name),
+                    &bound,
+                    Some(unique),
+                    &mut tokens,
+                )?;
+            }
+            lines.push(Line {
+                tokens,
+                expansion: Some(expansion.clone()),
+            });
+        }
+
+        // `self.lines` is a stack so the first line of the expansion has to go on top
+        self.lines.extend(lines.into_iter().rev());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::tests::{assemble, first_error_message};
+
+    #[test]
+    fn test_arguments() {
+        let source =
+            ".macro twice a, b=2\n.u8 \\a, \\b\n.u8 \\a\n.endm\ntwice 1\ntwice 3, 4\ntwice 5,";
+        assert_eq!(assemble(source), &[1, 2, 1, 3, 4, 3, 5, 2, 5]);
+
+        // Arguments can be whole expressions
+        let source = ".macro emit value\n.u8 \\value * 2\n.endm\nemit (1 + 2)\nemit max(1, 4)";
+        assert_eq!(assemble(source), &[6, 8]);
+
+        let source =
+            ".macro bytes first, rest:vararg\n.u8 \\first\n.u8 \\rest\n.endm\nbytes 1, 2, 3";
+        assert_eq!(assemble(source), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn test_unique_labels() {
+        let source =
+            "start:\n.macro here\nlabel_\\@: .u8 label_\\@ - start\n.endm\nhere\nhere\nhere";
+        assert_eq!(assemble(source), &[0, 1, 2]);
+    }
+
+    #[test]
+    fn test_nested() {
+        let source = "
+        .macro inner value
+        .u8 \\value
+        .endm
+        .macro outer value
+        inner \\value + 1
+        .macro defined_later
+        .u8 \\value
+        .endm
+        .endm
+        outer 1
+        defined_later
+        ";
+        assert_eq!(assemble(source), &[2, 1]);
+
+        let error = first_error_message(".macro forever\nforever\n.endm\nforever");
+        assert!(error.contains("exceeded the recursion limit"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let source =
+            ".macro inner\nmov r0,\n.endm\n.macro outer\ninner\n.endm\n.section .entry\nouter";
+        let error = first_error_message(source);
+        assert!(error.contains(" --> test.asm:2:"));
+        assert!(error.contains("note: in expansion of macro `inner`\n --> test.asm:5:1"));
+        assert!(error.contains("note: in expansion of macro `outer`\n --> test.asm:8:1"));
+
+        assert!(
+            first_error_message(".macro m a\n.u8 \\b\n.endm").contains("has no parameter named b")
+        );
+        assert!(
+            first_error_message(".macro m a\n.u8 \\a\n.endm\nm 1, 2")
+                .contains("takes 1 arguments but 2")
+        );
+        assert!(first_error_message(".macro m\n.u8 1").contains("missing its .endm"));
+        assert!(
+            first_error_message(".macro m\n.endm\n.macro m\n.endm").contains("already defined")
+        );
+        assert!(first_error_message(".endm").contains(".endm without a matching .macro"));
+    }
+}
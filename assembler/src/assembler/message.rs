@@ -0,0 +1,135 @@
+use std::iter::Peekable;
+
+use anyhow::{Context, Result};
+
+use crate::{
+    assembler::{AsmTokenIter, Assembler, AssemblerToken},
+    diagnostic::error_at,
+    tokens::Token,
+};
+
+impl Assembler {
+    /// Parses `.error "message"`, which fails the assembly with `message`
+    pub(super) fn parse_error<'a>(
+        &mut self,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<()> {
+        let (message, span) = self
+            .parse_string_argument(tokens)?
+            .context("Expected a message")?;
+
+        Err(error_at(span, String::from_utf8_lossy(&message)))
+    }
+
+    /// Parses `.warning "message"`, which shows `message` as a warning
+    pub(super) fn parse_warning<'a>(
+        &mut self,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<()> {
+        let (message, span) = self
+            .parse_string_argument(tokens)?
+            .context("Expected a message")?;
+
+        self.warnings
+            .push(error_at(span, String::from_utf8_lossy(&message)));
+
+        Ok(())
+    }
+
+    /// Parses `.print args...`, which prints every string and the value of every expression in
+    /// `args` separated by spaces
+    pub(super) fn parse_print<'a>(
+        &mut self,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<()> {
+        let span = tokens.peek().map(|token| token.span).unwrap_or_default();
+        let mut parts = Vec::new();
+
+        loop {
+            if let Some(AssemblerToken {
+                token: Token::Ascii(_),
+                ..
+            }) = tokens.peek()
+            {
+                let (string, _) = self.parse_string_argument(tokens)?.unwrap();
+                parts.push(String::from_utf8_lossy(&string).into_owned());
+                continue;
+            }
+
+            let Some((value, relocation, _, span)) = self.parse_expr_argument(tokens)? else {
+                break;
+            };
+            if relocation {
+                return Err(error_at(span, "Cannot print a value that isn't known yet"));
+            }
+            parts.push(value.to_string());
+        }
+
+        let (filename, _) = self.file(span.file);
+        println!("{filename}:{}: {}", span.line, parts.join(" "));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        assembler::{
+            Assembler,
+            tests::{default_assembler, format_diagnostics},
+        },
+        module::Module,
+    };
+
+    /// Parses `source` and returns the formatted first error and every warning
+    fn diagnostics(source: &str) -> (Option<String>, Vec<String>) {
+        format_diagnostics(&mut default_assembler(), source)
+    }
+
+    #[test]
+    fn test_error() {
+        let source = ".ifndef UART_BASE\n.error \"UART base not set\"\n.endif";
+        let (error, _) = diagnostics(source);
+        let error = error.unwrap();
+        assert!(error.starts_with("error: UART base not set"));
+        assert!(error.contains(" --> test.asm:2:8"));
+
+        assert!(
+            diagnostics(&format!(".equ UART_BASE, 1\n{source}"))
+                .0
+                .is_none()
+        );
+        assert!(Assembler::assemble(String::from("test.asm"), source.to_string()).is_err());
+    }
+
+    #[test]
+    fn test_warning() {
+        let source = "
+        .macro old_entry
+        .warning \"old_entry is deprecated\"
+        .endm
+        old_entry
+        .print \"table size\", 4 * 8, \"bytes\"
+        ";
+        let (error, warnings) = diagnostics(source);
+        assert!(error.is_none());
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("warning: old_entry is deprecated"));
+        assert!(warnings[0].contains("in expansion of macro `old_entry`"));
+
+        let mut assembler = Assembler::assemble(String::from("test.asm"), source.to_string())
+            .expect("Warnings don't stop the source from assembling");
+        assert_eq!(assembler.warning_count, 1);
+        assembler.warnings_as_errors = true;
+        let error = Module::try_from(assembler).err().unwrap().to_string();
+        assert!(error.contains("Warnings are treated as errors"));
+
+        let (error, _) = diagnostics(".print \"address\", later");
+        assert!(
+            error
+                .unwrap()
+                .contains("Cannot print a value that isn't known yet")
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use std::iter::Peekable;
+
+use anyhow::{Context, Result};
+
+use crate::{
+    assembler::{AsmTokenIter, Assembler, symbol_table::Type},
+    diagnostic::{Span, SpanContext, error_at},
+};
+
+/// A function started with `.func` that hasn't reached its `.endfunc` yet
+#[derive(Debug)]
+pub struct OpenFunction {
+    name: String,
+    section: usize,
+    start: u64,
+    span: Span,
+}
+
+impl Assembler {
+    /// Parses `.type symbol, function` or `.type symbol, object`
+    pub(super) fn parse_type<'a>(
+        &mut self,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<()> {
+        let span = tokens.peek().map(|token| token.span).unwrap_or_default();
+        let name = self
+            .parse_identifier_argument(tokens)?
+            .context("Expected symbol name")?;
+
+        let type_span = tokens.peek().map(|token| token.span).unwrap_or(span);
+        let type_ = match self.parse_identifier_argument(tokens)?.as_deref() {
+            Some("function") => Type::Function,
+            Some("object") => Type::Object,
+            _ => return Err(error_at(type_span, "Expected function or object")),
+        };
+
+        self.symbol_types.push((name, type_, span));
+
+        Ok(())
+    }
+
+    /// Parses `.size symbol, size`. The size is usually `. - symbol` at the end of the symbol
+    pub(super) fn parse_size<'a>(
+        &mut self,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<()> {
+        let span = tokens.peek().map(|token| token.span).unwrap_or_default();
+        let name = self
+            .parse_identifier_argument(tokens)?
+            .context("Expected symbol name")?;
+
+        let (size, relocation, _, size_span) = self
+            .parse_expr_argument(tokens)?
+            .context("Expected expression")?;
+        if relocation {
+            return Err(error_at(
+                size_span,
+                "Size must be a constant, but its value isn't known yet",
+            ));
+        }
+
+        self.symbol_sizes.push((name, size, span));
+
+        Ok(())
+    }
+
+    /// Parses `.func name`, which defines `name` as a function label. Its size is set by the
+    /// matching `.endfunc`
+    pub(super) fn parse_func<'a>(
+        &mut self,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<()> {
+        let span = tokens.peek().map(|token| token.span).unwrap_or_default();
+        let name = self
+            .parse_identifier_argument(tokens)?
+            .context("Expected function name")?;
+
+        if let Some(open) = &self.open_function {
+            return Err(error_at(
+                span,
+                format!(".func {name} is inside of .func {}", open.name),
+            ));
+        }
+
+        let (section, current) = self.sections.get_section()?;
+        let start = current.cursor() as u64;
+        self.symbols
+            .insert_symbol(name.clone(), start, Type::Function, Some(section))
+            .at(span)?;
+
+        // Labels starting with a dot belong to the function like they would after `name:`
+        self.label_scope = Some(name.clone());
+        self.open_function = Some(OpenFunction {
+            name,
+            section,
+            start,
+            span,
+        });
+
+        Ok(())
+    }
+
+    /// Parses `.endfunc`, which sets the size of the function to the bytes emitted since `.func`
+    pub(super) fn parse_endfunc<'a>(
+        &mut self,
+        _tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<()> {
+        let open = self
+            .open_function
+            .take()
+            .context(".endfunc without a matching .func")?;
+
+        let (section, current) = self.sections.get_section()?;
+        if section != open.section {
+            return Err(error_at(
+                open.span,
+                format!(".func {} ends in a different section", open.name),
+            ));
+        }
+
+        // Moving the cursor backwards with `.org` can end the function before it starts
+        let size = (current.cursor() as u64)
+            .checked_sub(open.start)
+            .ok_or_else(|| {
+                error_at(
+                    open.span,
+                    format!(".func {} ends before it starts", open.name),
+                )
+            })?;
+        if let Some(symbol) = self.symbols.get_symbol_mut(&open.name) {
+            symbol.size = Some(size);
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn check_unterminated_function(&mut self) -> Option<anyhow::Error> {
+        let open = self.open_function.take()?;
+        Some(error_at(
+            open.span,
+            format!(".func {} is missing its .endfunc", open.name),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::{
+        symbol_table::Type,
+        tests::{build, first_error_message},
+    };
+
+    #[test]
+    fn test_metadata() {
+        let source = "
+        .type table, object
+        .section .entry
+        .func memcpy
+        .loop: jnz .loop
+        halt
+        .endfunc
+        start: halt
+        .section .data
+        table: .u32 1, 2, 3
+        .size table, . - table
+        .type start, function
+        .size start, 1
+        ";
+        let module = build(source).expect("Module should build");
+
+        let memcpy = module.symbols.get_symbol("memcpy").unwrap();
+        assert_eq!(memcpy.type_, Type::Function);
+        assert_eq!(memcpy.size, Some(6));
+        assert!(module.symbols.get_symbol("memcpy.loop").is_some());
+
+        let table = module.symbols.get_symbol("table").unwrap();
+        assert_eq!((table.type_, table.size), (Type::Object, Some(12)));
+        let start = module.symbols.get_symbol("start").unwrap();
+        assert_eq!((start.type_, start.size), (Type::Function, Some(1)));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = first_error_message(".section .entry\n.func a\n.func b");
+        assert!(error.contains(".func b is inside of .func a"));
+        assert!(error.contains(" --> test.asm:3:7"));
+
+        let error = first_error_message(".section .entry\n.func a\nhalt");
+        assert!(error.contains(".func a is missing its .endfunc"));
+        assert!(error.contains(" --> test.asm:2:7"));
+
+        assert!(first_error_message(".endfunc").contains(".endfunc without a matching .func"));
+
+        let error = first_error_message(".section .entry\nhalt\n.func a\n. = 0\n.endfunc");
+        assert!(error.contains(".func a ends before it starts"));
+        assert!(error.contains(" --> test.asm:3:7"));
+        assert!(first_error_message(".type a, label").contains("Expected function or object"));
+
+        let error = build(".equ A, 1\n.type A, object")
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("Only labels can have a type, but A is a constant"));
+        assert!(error.contains(" --> test.asm:2:7"));
+        let error = build(".size missing, 4").err().unwrap().to_string();
+        assert!(error.contains("Symbol missing has no definition"));
+    }
+}
@@ -0,0 +1,328 @@
+use std::{collections::HashSet, iter::Peekable};
+
+use anyhow::Result;
+
+use crate::{
+    assembler::{
+        AsmTokenIter, Assembler, AssemblerToken, Line,
+        directive::{expect_comma, expect_end_of_line, should_return_none},
+        macros::{split_arguments, substitute},
+    },
+    diagnostic::{Span, SpanContext, error_at},
+    expression::parse_spanned_expr,
+    tokens::{Directive, Token},
+};
+
+/// How many times a block can be repeated. Anything larger is most likely a mistake that would
+/// otherwise take forever to assemble
+const REPEAT_LIMIT: u64 = 1 << 20;
+
+/// Returns the symbol bound by a `.rept`, `.irp` or `.irpc` line, if it has one
+pub(super) fn repetition_symbol(tokens: &[AssemblerToken]) -> Option<&str> {
+    let directive = match tokens.first()?.token {
+        Token::Directive(directive) if directive.is_repetition() => directive,
+        _ => return None,
+    };
+
+    let symbol = match directive {
+        // The counter comes after the count, which is an arbitrary expression
+        Directive::Rept => {
+            let tokens = match tokens.last()?.token {
+                Token::Newline => &tokens[..tokens.len() - 1],
+                _ => tokens,
+            };
+            match tokens {
+                [_, .., comma, symbol] if matches!(comma.token, Token::Comma) => symbol,
+                _ => return None,
+            }
+        }
+        _ => tokens.get(1)?,
+    };
+
+    match &symbol.token {
+        Token::Identifier(name) => Some(name),
+        _ => None,
+    }
+}
+
+/// Returns the labels defined at the start of `tokens`, a line can define more than one
+fn defined_labels(tokens: &[AssemblerToken]) -> impl Iterator<Item = &str> {
+    tokens.chunks(2).map_while(|pair| match pair {
+        [
+            AssemblerToken {
+                token: Token::Identifier(name),
+                ..
+            },
+            AssemblerToken {
+                token: Token::Colon,
+                ..
+            },
+        ] => Some(name.as_str()),
+        _ => None,
+    })
+}
+
+/// Renames every label in `labels` to `label@unique` in `lines`, both where it is defined and
+/// where it is used. `@` can't be part of an identifier in the source, so the new names can't
+/// clash with any other symbol
+fn rename_labels(lines: &mut [Line], labels: &HashSet<String>, unique: usize) {
+    for token in lines.iter_mut().flat_map(|line| line.tokens.iter_mut()) {
+        if let Token::Identifier(name) = &mut token.token
+            && labels.contains(name.as_str())
+        {
+            *name = format!("{name}@{unique}");
+        }
+    }
+}
+
+impl Assembler {
+    /// Parses a repetition and every line up to the matching `.endr`, and queues the body once
+    /// for every iteration. `span` is the span of the directive.
+    ///
+    /// - `.rept count, counter` repeats the body `count` times, and `\counter` is the index of
+    ///   the iteration. The counter is optional
+    /// - `.irp symbol, values...` repeats the body for every value with `\symbol` set to it
+    /// - `.irpc symbol, "chars"` repeats the body for every character of the string
+    ///
+    /// Labels defined in the body are renamed in every iteration, so each iteration defines its
+    /// own copy and refers to it. That also means they can't be used outside of the body. `\@`
+    /// is replaced with a different number in every iteration as well
+    pub(super) fn parse_repetition<'a>(
+        &mut self,
+        directive: Directive,
+        span: Span,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+        line: &Line,
+    ) -> Result<()> {
+        let (symbol, values) = match directive {
+            Directive::Rept => {
+                if should_return_none(tokens) {
+                    return Err(error_at(span, "Expected a repeat count"));
+                }
+                let (count, count_span) = parse_spanned_expr(tokens)?;
+                let (count, relocation) = self
+                    .evaluate_non_operand_expression(&count)
+                    .at(count_span)?;
+                if relocation {
+                    return Err(error_at(
+                        count_span,
+                        "Repeat count must be a constant, but its value isn't known yet",
+                    ));
+                }
+                if count > REPEAT_LIMIT {
+                    return Err(error_at(
+                        count_span,
+                        format!("Repeat count {count} is larger than the limit of {REPEAT_LIMIT}"),
+                    ));
+                }
+                expect_comma(tokens)?;
+
+                let symbol = self.parse_identifier_argument(tokens)?;
+                expect_end_of_line(tokens)?;
+
+                let values = (0..count)
+                    .map(|i| {
+                        vec![AssemblerToken {
+                            token: Token::Number(i),
+                            span,
+                        }]
+                    })
+                    .collect();
+                (symbol, values)
+            }
+            Directive::Irp => {
+                let symbol = self
+                    .parse_identifier_argument(tokens)?
+                    .ok_or_else(|| error_at(span, "Expected a symbol name"))?;
+                let (values, _) = split_arguments(tokens);
+                (Some(symbol), values)
+            }
+            Directive::Irpc => {
+                let symbol = self
+                    .parse_identifier_argument(tokens)?
+                    .ok_or_else(|| error_at(span, "Expected a symbol name"))?;
+                let (string, string_span) = self
+                    .parse_string_argument(tokens)?
+                    .ok_or_else(|| error_at(span, "Expected a string"))?;
+                expect_end_of_line(tokens)?;
+
+                let mut values = Vec::new();
+                for ch in String::from_utf8_lossy(&string).chars() {
+                    let tokens = Self::tokenize(&ch.to_string()).at(string_span)?;
+                    values.push(
+                        tokens
+                            .into_iter()
+                            .filter(|token| !matches!(token.token, Token::Newline))
+                            .map(|token| AssemblerToken {
+                                token: token.token,
+                                span: string_span,
+                            })
+                            .collect(),
+                    );
+                }
+                (Some(symbol), values)
+            }
+            _ => unreachable!("{} is not a repetition", directive.name()),
+        };
+
+        // Collect the body. The `\@` of nested repetitions is left alone so that it's unique in
+        // each of their iterations too
+        let mut body = Vec::new();
+        let mut depth = 0usize;
+        loop {
+            let Some(body_line) = self.lines.pop() else {
+                return Err(error_at(
+                    span,
+                    format!("{} is missing its .endr", directive.name()),
+                ));
+            };
+
+            match body_line.tokens.first().map(|token| &token.token) {
+                Some(Token::Directive(directive)) if directive.is_repetition() => depth += 1,
+                Some(Token::Directive(Directive::Endr)) if depth == 0 => {
+                    expect_end_of_line(&mut body_line.tokens[1..].iter().peekable())?;
+                    break;
+                }
+                Some(Token::Directive(Directive::Endr)) => depth -= 1,
+                _ => {}
+            }
+
+            body.push((depth > 0, body_line.tokens));
+        }
+
+        let mut lines = Vec::with_capacity(body.len() * values.len());
+        for value in values {
+            let unique = self.macro_count;
+            self.macro_count += 1;
+
+            let first = lines.len();
+            let mut labels = HashSet::new();
+            let args = [value];
+            for (nested, body_line) in body.iter() {
+                let mut tokens = Vec::with_capacity(body_line.len());
+                for token in body_line {
+                    substitute(
+                        token,
+                        |name| (symbol.as_deref() == Some(name)).then_some(0),
+                        &args,
+                        (!nested).then_some(unique),
+                        &mut tokens,
+                    )?;
+                }
+                // Nested repetitions rename their own labels when they are expanded
+                if !nested {
+                    labels.extend(defined_labels(&tokens).map(str::to_string));
+                }
+                lines.push(Line {
+                    tokens,
+                    expansion: line.expansion.clone(),
+                });
+            }
+            rename_labels(&mut lines[first..], &labels, unique);
+        }
+
+        // `self.lines` is a stack so the first line of the first iteration has to go on top
+        self.lines.extend(lines.into_iter().rev());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::tests::{assemble, first_error_message};
+
+    #[test]
+    fn test_rept() {
+        assert_eq!(assemble(".rept 3\n.u8 7\n.endr"), &[7, 7, 7]);
+        assert_eq!(assemble(".rept 4, i\n.u8 \\i * \\i\n.endr"), &[0, 1, 4, 9]);
+        assert_eq!(assemble(".equ N, 0\n.rept N\n.u8 1\n.endr\n.u8 2"), &[2]);
+
+        // Labels made unique with `\@` are different in every iteration, even in nested blocks
+        let source = "
+        start:
+        .rept 2, i
+        .rept 2, j
+        entry_\\@: .u8 entry_\\@ - start
+        .endr
+        outer_\\@: .u8 \\i
+        .endr
+        ";
+        assert_eq!(assemble(source), &[0, 1, 0, 3, 4, 1]);
+    }
+
+    #[test]
+    fn test_labels() {
+        // Every iteration defines its own labels, including the scope of local labels
+        let source = "
+        start:
+        .rept 3
+        loop: .u8 loop - start
+        .next: .u8 .next - loop
+        .endr
+        ";
+        assert_eq!(assemble(source), &[0, 1, 2, 1, 4, 1]);
+
+        let source = "
+        start:
+        .rept 2
+        outer: .u8 outer - start
+        .rept 2
+        inner: .u8 inner - outer
+        .endr
+        .endr
+        ";
+        assert_eq!(assemble(source), &[0, 1, 2, 3, 1, 2]);
+
+        // The labels of the body don't clash with the ones outside of it
+        assert_eq!(
+            assemble("start:\n.rept 1\nloop: .u8 1\n.endr\nloop: .u8 loop - start"),
+            &[1, 1]
+        );
+    }
+
+    #[test]
+    fn test_irp() {
+        assert_eq!(
+            assemble(".irp v, 1, (2 + 3), max(4, 6)\n.u8 \\v\n.endr"),
+            &[1, 5, 6]
+        );
+        assert_eq!(assemble(".irp v\n.u8 \\v\n.endr\n.u8 1"), &[1]);
+        assert_eq!(assemble(".irpc c, \"123\"\n.u8 \\c\n.endr"), &[1, 2, 3]);
+
+        // Repetitions inside of macros can use both the macro's parameters and their own symbol
+        let source = "
+        .macro table base, count
+        .rept \\count, i
+        .u8 \\base + \\i
+        .endr
+        .irpc c, \"ab\"
+        label_\\c: .u8 label_\\c - start
+        .endr
+        .endm
+        start:
+        table 10, 3
+        ";
+        assert_eq!(assemble(source), &[10, 11, 12, 3, 4]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = first_error_message(".section .entry\n.rept later\n.endr\n.equ later, 1");
+        assert!(error.contains("must be a constant"));
+        assert!(error.contains(" --> test.asm:2:7"));
+
+        let error = first_error_message(".section .entry\nstart:\n.rept start\n.endr");
+        assert!(error.contains("Invalid use of label"));
+        assert!(error.contains(" --> test.asm:3:7"));
+
+        let error = first_error_message(".section .entry\n.rept 1 << 40\n.endr");
+        assert!(error.contains("larger than the limit"));
+
+        let error = first_error_message(".section .entry\n.irp v, 1\n.rept 2\n.endr\n.u8 \\v");
+        assert!(error.contains(".irp is missing its .endr"));
+        assert!(error.contains(" --> test.asm:2:1"));
+
+        assert!(first_error_message(".endr").contains(".endr without a matching .rept"));
+    }
+}
@@ -0,0 +1,482 @@
+use std::iter::Peekable;
+
+use anyhow::{Context, Result, bail};
+
+use crate::{
+    assembler::{
+        AsmTokenIter, Assembler, AssemblerToken, directive::expect_end_of_line, symbol_table::Type,
+    },
+    diagnostic::{Span, SpanContext, error_at},
+    expression::Node,
+    size::Size,
+    tokens::{Directive, Token},
+};
+
+/// The layout of a record defined with `.struct`
+#[derive(Debug)]
+pub struct Struct {
+    name: String,
+    fields: Vec<Field>,
+    /// The size including the padding needed to keep the struct aligned in an array
+    size: u64,
+}
+
+#[derive(Debug)]
+struct Field {
+    name: String,
+    offset: u64,
+    /// The size of each element, or `None` if the field was declared with `.skip`
+    size: Option<Size>,
+    count: u64,
+    span: Span,
+}
+
+/// A field set by `.instance`
+struct Initializer {
+    field: String,
+    offset: u64,
+    size: Size,
+    value: u64,
+    relocation: bool,
+    expr: Box<Node>,
+    /// The span of the field name
+    span: Span,
+    expr_span: Span,
+}
+
+/// A `.struct` that hasn't reached its `.endstruct` yet
+#[derive(Debug)]
+pub struct OpenStruct {
+    layout: Struct,
+    span: Span,
+    offset: u64,
+    /// The largest `.align` in the struct
+    alignment: u64,
+    /// Field labels that are waiting for the directive that gives them a size
+    pending: Vec<(String, Span)>,
+    /// The label scope from before the struct, which is restored by `.endstruct`
+    outer_scope: Option<String>,
+}
+
+impl OpenStruct {
+    /// Adds every pending label as a field of `count` elements of `size`, and moves past them
+    fn add_fields(&mut self, size: Option<Size>, count: u64) -> Result<()> {
+        let bytes = size
+            .map_or(1, |size| size.bytes())
+            .checked_mul(count)
+            .and_then(|bytes| self.offset.checked_add(bytes))
+            .context("Struct is too large")?;
+
+        for (name, span) in self.pending.drain(..) {
+            self.layout.fields.push(Field {
+                name,
+                offset: self.offset,
+                size,
+                count,
+                span,
+            });
+        }
+        self.offset = bytes;
+
+        Ok(())
+    }
+}
+
+impl Assembler {
+    /// Parses `.struct name`. Every line up to `.endstruct` declares fields instead of emitting
+    /// anything:
+    ///
+    /// - `field:` names the field at the current offset. Since the struct is the label scope,
+    ///   `.field:` works too
+    /// - `.u8`, `.u16`, `.u32` and `.u64` take an optional element count and reserve space
+    /// - `.skip n` reserves `n` bytes
+    /// - `.align n` aligns the next field, and the size of the struct is rounded up to the
+    ///   largest alignment
+    ///
+    /// `.endstruct` defines the constants `name.field` for every field and `name.size`
+    pub(super) fn parse_struct<'a>(
+        &mut self,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<()> {
+        let span = tokens.peek().map(|token| token.span).unwrap_or_default();
+        let name = self
+            .parse_identifier_argument(tokens)?
+            .context("Expected struct name")?;
+        if self.structs.contains_key(&name) {
+            return Err(error_at(span, format!("Struct {name} is already defined")));
+        }
+
+        let outer_scope = self.label_scope.replace(name.clone());
+        self.open_struct = Some(OpenStruct {
+            layout: Struct {
+                name,
+                fields: Vec::new(),
+                size: 0,
+            },
+            span,
+            offset: 0,
+            alignment: 1,
+            pending: Vec::new(),
+            outer_scope,
+        });
+
+        Ok(())
+    }
+
+    /// Parses a statement between `.struct` and `.endstruct`
+    pub(super) fn parse_struct_member<'a>(
+        &mut self,
+        token: &AssemblerToken,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<()> {
+        let open = self
+            .open_struct
+            .as_mut()
+            .expect("Struct members are only parsed inside of a struct");
+
+        let directive = match &token.token {
+            Token::Newline => return Ok(()),
+            Token::Identifier(name) => {
+                let Some(AssemblerToken {
+                    token: Token::Colon,
+                    ..
+                }) = tokens.next()
+                else {
+                    return Err(error_at(token.span, "Expected colon after the field name"));
+                };
+
+                let prefix = format!("{}.", open.layout.name);
+                let name = name.strip_prefix(&prefix).unwrap_or(name);
+                let exists = open.layout.fields.iter().any(|field| field.name == name)
+                    || open.pending.iter().any(|(pending, _)| pending == name);
+                if exists {
+                    return Err(error_at(
+                        token.span,
+                        format!(
+                            "Struct {} already has a field named {name}",
+                            open.layout.name
+                        ),
+                    ));
+                }
+
+                open.pending.push((name.to_string(), token.span));
+                return Ok(());
+            }
+            Token::Directive(directive) => *directive,
+            Token::Mnemonic(_) => bail!("Instructions can't be used inside of .struct"),
+            other => return Err(error_at(token.span, format!("Unknown token {other:?}"))),
+        };
+
+        match directive {
+            Directive::U8 | Directive::U16 | Directive::U32 | Directive::U64 => {
+                let size = match directive {
+                    Directive::U8 => Size::U8,
+                    Directive::U16 => Size::U16,
+                    Directive::U32 => Size::U32,
+                    _ => Size::U64,
+                };
+                let count = self.parse_struct_constant(tokens, "Element count")?;
+                self.open_struct
+                    .as_mut()
+                    .expect("The struct was checked above")
+                    .add_fields(Some(size), count.map_or(1, |(count, _)| count))?;
+            }
+            Directive::Skip => {
+                let (count, _) = self
+                    .parse_struct_constant(tokens, "Skip count")?
+                    .context("Expected expression")?;
+                self.open_struct
+                    .as_mut()
+                    .expect("The struct was checked above")
+                    .add_fields(None, count)?;
+            }
+            Directive::Align => {
+                let (align, span) = self
+                    .parse_struct_constant(tokens, "Alignment")?
+                    .context("Expected expression")?;
+                if !align.is_power_of_two() {
+                    return Err(error_at(
+                        span,
+                        format!("Alignment must be a nonzero power of two, but it is {align}"),
+                    ));
+                }
+
+                let open = self
+                    .open_struct
+                    .as_mut()
+                    .expect("The struct was checked above");
+                open.add_fields(None, 0)?;
+                open.offset = open
+                    .offset
+                    .checked_next_multiple_of(align)
+                    .context("Struct is too large")?;
+                open.alignment = open.alignment.max(align);
+            }
+            Directive::Endstruct => self.end_struct()?,
+            Directive::Struct => bail!("Structs can't be nested"),
+            other => bail!("{} can't be used inside of .struct", other.name()),
+        }
+
+        expect_end_of_line(tokens)
+    }
+
+    /// Parses an optional constant argument of a directive inside of a struct, along with its span
+    fn parse_struct_constant<'a>(
+        &self,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+        what: &str,
+    ) -> Result<Option<(u64, Span)>> {
+        let Some((value, relocation, _, span)) = self.parse_expr_argument(tokens)? else {
+            return Ok(None);
+        };
+        if relocation {
+            return Err(error_at(span, format!("{what} must be a constant")));
+        }
+
+        Ok(Some((value, span)))
+    }
+
+    fn end_struct(&mut self) -> Result<()> {
+        let mut open = self
+            .open_struct
+            .take()
+            .expect("Struct members are only parsed inside of a struct");
+        self.label_scope = open.outer_scope.take();
+
+        // Labels at the end of the struct are empty fields
+        open.add_fields(None, 0)?;
+        let mut layout = open.layout;
+        layout.size = open
+            .offset
+            .checked_next_multiple_of(open.alignment)
+            .context("Struct is too large")?;
+
+        for field in layout.fields.iter() {
+            self.symbols
+                .insert_symbol(
+                    format!("{}.{}", layout.name, field.name),
+                    field.offset,
+                    Type::Constant,
+                    None,
+                )
+                .at(field.span)?;
+        }
+        self.symbols
+            .insert_symbol(
+                format!("{}.size", layout.name),
+                layout.size,
+                Type::Constant,
+                None,
+            )
+            .at(open.span)?;
+
+        self.structs.insert(layout.name.clone(), layout);
+
+        Ok(())
+    }
+
+    /// Reports a `.struct` that was never closed
+    pub(super) fn check_unterminated_struct(&mut self) -> Option<anyhow::Error> {
+        let open = self.open_struct.take()?;
+        Some(error_at(
+            open.span,
+            format!(".struct {} is missing its .endstruct", open.layout.name),
+        ))
+    }
+
+    /// Parses `.instance name, field = value, ...`, which emits an instance of the struct `name`
+    /// with the given fields set and everything else zeroed
+    pub(super) fn parse_instance<'a>(
+        &mut self,
+        tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    ) -> Result<()> {
+        let span = tokens.peek().map(|token| token.span).unwrap_or_default();
+        let name = self
+            .parse_identifier_argument(tokens)?
+            .context("Expected struct name")?;
+        let size = self
+            .structs
+            .get(&name)
+            .map(|layout| layout.size)
+            .ok_or_else(|| error_at(span, format!("Struct {name} is not defined")))?;
+
+        let mut values = Vec::new();
+        while let Some(token) = tokens.peek()
+            && !matches!(token.token, Token::Newline)
+        {
+            let token = tokens.next().expect("The token was peeked above");
+            let Token::Identifier(field_name) = &token.token else {
+                return Err(error_at(token.span, "Expected field name"));
+            };
+            match tokens.next() {
+                Some(AssemblerToken {
+                    token: Token::Equal,
+                    ..
+                }) => {}
+                _ => return Err(error_at(token.span, "Expected = after the field name")),
+            }
+            let (value, relocation, expr, expr_span) = self
+                .parse_expr_argument(tokens)?
+                .ok_or_else(|| error_at(token.span, "Expected a value for the field"))?;
+
+            let layout = &self.structs[&name];
+            let field = layout
+                .fields
+                .iter()
+                .find(|field| field.name == *field_name)
+                .ok_or_else(|| {
+                    error_at(
+                        token.span,
+                        format!("Struct {name} has no field named {field_name}"),
+                    )
+                })?;
+            let Some(field_size) = field.size.filter(|_| field.count == 1) else {
+                return Err(error_at(
+                    token.span,
+                    format!("Field {field_name} isn't a single .u8, .u16, .u32 or .u64"),
+                ));
+            };
+            if values
+                .iter()
+                .any(|value: &Initializer| value.field == *field_name)
+            {
+                return Err(error_at(
+                    token.span,
+                    format!("Field {field_name} is set more than once"),
+                ));
+            }
+
+            values.push(Initializer {
+                field: field_name.clone(),
+                offset: field.offset,
+                size: field_size,
+                value,
+                relocation,
+                expr,
+                span: token.span,
+                expr_span,
+            });
+        }
+
+        values.sort_by_key(|value| value.offset);
+
+        let mut offset = 0;
+        for value in values {
+            if value.offset < offset {
+                return Err(error_at(
+                    value.span,
+                    format!("Field {} overlaps another field that is set", value.field),
+                ));
+            }
+
+            let (_, section) = self.sections.get_section_mut()?;
+            section.reserve(value.offset - offset);
+            self.embed_value(
+                value.size,
+                value.value,
+                value.relocation,
+                value.expr,
+                value.expr_span,
+            )?;
+            offset = value.offset + value.size.bytes();
+        }
+
+        let (_, section) = self.sections.get_section_mut()?;
+        section.reserve(size - offset);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::tests::{build, first_error_message};
+
+    const UART: &str = "
+    .struct uart
+    data: .u8
+    status: .u8
+    .align 4
+    control: .u32
+    .buffer: .u8 6
+    .align 8
+    .endstruct
+    ";
+
+    #[test]
+    fn test_layout() {
+        let source = format!(
+            "{UART}
+            .section .entry
+            .u8 uart.data, uart.status, uart.control, uart.buffer, uart.size
+            "
+        );
+        let module = build(&source).expect("Module should build");
+        assert_eq!(module.sections[0].data.get_ref(), &[0, 1, 4, 8, 16]);
+
+        // The label scope is restored after the struct
+        let source = "
+        .section .entry
+        start:
+        .struct empty
+        end:
+        .endstruct
+        .u8 .here - start, empty.end, empty.size
+        .here:
+        ";
+        assert_eq!(
+            build(source).expect("Module should build").sections[0]
+                .data
+                .get_ref(),
+            &[3, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_instance() {
+        let source = format!(
+            "{UART}
+            .section .entry
+            .instance uart, control = 0x12345678, data = end - start
+            start:
+            .instance uart
+            end:
+            "
+        );
+        let module = build(&source).expect("Module should build");
+        assert_eq!(
+            module.sections[0].data.get_ref(),
+            &[
+                16, 0, 0, 0, 0x78, 0x56, 0x34, 0x12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                0, 0, 0, 0, 0, 0, 0, 0, 0
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = first_error_message(".struct point\nx: .u32\nmov r0, 1\n.endstruct");
+        assert!(error.contains("Instructions can't be used inside of .struct"));
+        assert!(error.contains(" --> test.asm:3:1"));
+
+        let error = first_error_message(".struct point\nx: .u32\nx: .u32");
+        assert!(error.contains("already has a field named x"));
+        assert!(
+            first_error_message(".struct point\nx: .u32")
+                .contains(".struct point is missing its .endstruct")
+        );
+        assert!(
+            first_error_message(".endstruct").contains(".endstruct without a matching .struct")
+        );
+
+        let error = first_error_message(".struct point\nx: .u8\n.align 3\n.endstruct");
+        assert!(error.contains("Alignment must be a nonzero power of two, but it is 3"));
+        assert!(error.contains(" --> test.asm:3:8"));
+        assert!(first_error_message(".struct point\n.align 0\n.endstruct").contains("but it is 0"));
+
+        let source = format!("{UART}\n.section .entry\n.instance uart, buffer = 1");
+        assert!(first_error_message(&source).contains("Field buffer isn't a single"));
+        let source = format!("{UART}\n.section .entry\n.instance uart, parity = 1");
+        assert!(first_error_message(&source).contains("Struct uart has no field named parity"));
+    }
+}
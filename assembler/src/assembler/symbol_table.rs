@@ -1,15 +1,18 @@
 use core::fmt;
-use std::{
-    collections::{HashMap, hash_map::Entry},
-    ffi::os_str::Display,
-};
+use std::collections::{HashMap, hash_map::Entry};
 
-use anyhow::{Result, anyhow, bail};
+use anyhow::{Result, bail};
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Type {
     Label,
     Constant,
+    /// A label set to `function` with `.type` or defined with `.func`
+    Function,
+    /// A label set to `object` with `.type`
+    Object,
+    /// A constant defined with `.set`, which can be set again
+    Variable,
 }
 
 impl Type {
@@ -17,13 +20,43 @@ impl Type {
         match self {
             Type::Label => "Label",
             Type::Constant => "Constant",
+            Type::Function => "Function",
+            Type::Object => "Object",
+            Type::Variable => "Variable",
         }
     }
 }
 
 impl fmt::Display for Type {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.as_str())
+        f.pad(self.as_str())
+    }
+}
+
+/// Which modules can see a symbol
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Binding {
+    /// Only the module that defines it
+    Local,
+    /// Every module
+    Global,
+    /// Every module, unless another module defines a global symbol with the same name
+    Weak,
+}
+
+impl Binding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Binding::Local => "Local",
+            Binding::Global => "Global",
+            Binding::Weak => "Weak",
+        }
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.as_str())
     }
 }
 
@@ -32,6 +65,9 @@ pub struct Symbol {
     pub section_index: Option<usize>,
     pub type_: Type,
     pub value: u64,
+    pub binding: Binding,
+    /// The size in bytes, set with `.size` or `.endfunc`
+    pub size: Option<u64>,
 }
 
 #[derive(Debug)]
@@ -61,6 +97,8 @@ impl SymbolTable {
                     section_index: section,
                     type_,
                     value,
+                    binding: Binding::Local,
+                    size: None,
                 });
                 Ok(())
             }
@@ -68,6 +106,47 @@ impl SymbolTable {
         }
     }
 
+    /// Defines `id` as a variable with `value`, or changes its value if it already is one
+    pub fn set_variable(&mut self, id: String, value: u64) -> Result<()> {
+        match self.symbols.get_mut(&id) {
+            Some(symbol) if symbol.type_ == Type::Variable => {
+                symbol.value = value;
+                Ok(())
+            }
+            Some(_) => bail!("Symbol already defined"),
+            None => self.insert_symbol(id, value, Type::Variable, None),
+        }
+    }
+
+    /// Changes the binding of `id`. Returns false if the symbol isn't defined
+    pub fn set_binding(&mut self, id: &str, binding: Binding) -> bool {
+        match self.symbols.get_mut(id) {
+            Some(symbol) => {
+                symbol.binding = binding;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get_symbol_mut(&mut self, id: &str) -> Option<&mut Symbol> {
+        self.symbols.get_mut(id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Symbol)> {
+        self.symbols
+            .iter()
+            .map(|(id, symbol)| (id.as_str(), *symbol))
+    }
+
+    /// Returns the value of `id` if it's a variable
+    pub fn get_variable(&self, id: &str) -> Option<u64> {
+        self.symbols
+            .get(id)
+            .filter(|symbol| symbol.type_ == Type::Variable)
+            .map(|symbol| symbol.value)
+    }
+
     #[track_caller]
     pub fn get_symbol(&self, id: &str) -> Option<Symbol> {
         assert_ne!(id, ".", "The location counter should never be requested as a symbol");
@@ -0,0 +1,224 @@
+use std::fmt::{self, Display};
+
+use anyhow::Result;
+
+/// A region of the source code. `start` and `end` are byte offsets while `line` and `column` are
+/// both 1-based and point at the first character of the span
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+    /// The file the span is in. 0 is the file being assembled and every file it includes is
+    /// numbered after it
+    pub file: usize,
+}
+
+impl Span {
+    /// Returns the span of `text[start..end]` where `text` is the source code `self` covers
+    pub fn subspan(self, text: &str, start: usize, end: usize) -> Span {
+        let prefix = &text[..start];
+        let (line, column) = match prefix.rfind('\n') {
+            Some(i) => (
+                self.line + prefix.matches('\n').count(),
+                prefix[i + 1..].chars().count() + 1,
+            ),
+            None => (self.line, self.column + prefix.chars().count()),
+        };
+
+        Span {
+            start: self.start + start,
+            end: self.start + end,
+            line,
+            column,
+            file: self.file,
+        }
+    }
+
+    /// Returns a span that starts at `self` and ends at the end of `other`
+    pub fn to(self, other: Span) -> Span {
+        if other.end > self.end {
+            Span {
+                end: other.end,
+                ..self
+            }
+        } else {
+            self
+        }
+    }
+}
+
+/// An error that knows which part of the source code caused it
+#[derive(Debug)]
+pub struct SpanError {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Display for SpanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for SpanError {}
+
+/// Creates an error pointing at `span`
+pub fn error_at(span: Span, message: impl Display) -> anyhow::Error {
+    SpanError {
+        span,
+        message: message.to_string(),
+    }
+    .into()
+}
+
+/// Returns the span attached to `error` if it has one
+pub fn error_span(error: &anyhow::Error) -> Option<Span> {
+    error.downcast_ref::<SpanError>().map(|e| e.span)
+}
+
+pub trait SpanContext<T> {
+    /// Attaches `span` to the error. If the error already points somewhere more specific, that
+    /// span is kept instead
+    fn at(self, span: Span) -> Result<T>;
+}
+
+impl<T> SpanContext<T> for Result<T> {
+    fn at(self, span: Span) -> Result<T> {
+        self.map_err(|e| {
+            if e.is::<SpanError>() {
+                e
+            } else {
+                error_at(span, format!("{e:#}"))
+            }
+        })
+    }
+}
+
+/// Formats `message` in the same style as rustc, showing the line of source code `span` is on
+/// with the span underlined
+///
+/// ```text
+/// error: Invalid scale
+///  --> test.asm:3:15
+///   |
+/// 3 | mov r3, [r1 + r2*3 + label]
+///   |               ^^^^
+/// ```
+pub fn render(filename: &str, source: &str, span: Span, message: impl Display) -> String {
+    render_with_level("error", filename, source, span, message)
+}
+
+/// Same as `render` but for extra context attached to an error, like where a macro was invoked
+pub fn render_note(filename: &str, source: &str, span: Span, message: impl Display) -> String {
+    render_with_level("note", filename, source, span, message)
+}
+
+/// Same as `render` but for problems that don't stop the source from assembling
+pub fn render_warning(filename: &str, source: &str, span: Span, message: impl Display) -> String {
+    render_with_level("warning", filename, source, span, message)
+}
+
+fn render_with_level(
+    level: &str,
+    filename: &str,
+    source: &str,
+    span: Span,
+    message: impl Display,
+) -> String {
+    let start = span.start.min(source.len());
+    let line_start = source[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_end = source[start..]
+        .find('\n')
+        .map(|i| start + i)
+        .unwrap_or(source.len());
+
+    let line = source[line_start..line_end].trim_end_matches('\r');
+
+    // Tabs are kept in the padding so that the underline lines up with the source line no matter
+    // how wide the terminal displays a tab
+    let padding: String = source[line_start..start]
+        .chars()
+        .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    let end = span.end.clamp(start, line_end);
+    let width = source[start..end].chars().count().max(1);
+
+    let line_number = span.line.to_string();
+    let gutter = " ".repeat(line_number.len());
+
+    format!(
+        "{level}: {message}\n\
+         {gutter}--> {filename}:{}:{}\n\
+         {gutter} |\n\
+         {line_number} | {line}\n\
+         {gutter} | {padding}{}",
+        span.line,
+        span.column,
+        "^".repeat(width)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let source = "mov r0, 1\nmov r3, [r1 + r2*3 + label]\n";
+        let span = Span {
+            start: 24,
+            end: 28,
+            line: 2,
+            column: 15,
+            file: 0,
+        };
+
+        assert_eq!(
+            render("test.asm", source, span, "Invalid scale"),
+            "error: Invalid scale\n \
+             --> test.asm:2:15\n  \
+             |\n\
+             2 | mov r3, [r1 + r2*3 + label]\n  \
+             |               ^^^^"
+        );
+
+        // A span pointing at a newline is shown as a single caret at the end of the line
+        let span = Span {
+            start: 9,
+            end: 10,
+            line: 1,
+            column: 10,
+            file: 0,
+        };
+        let rendered = render("test.asm", source, span, "Expected comma");
+        assert!(rendered.ends_with("1 | mov r0, 1\n  |          ^"));
+    }
+
+    #[test]
+    fn test_span_context() {
+        let span = Span {
+            start: 1,
+            end: 2,
+            line: 1,
+            column: 2,
+            file: 0,
+        };
+        let inner = Span {
+            start: 5,
+            end: 6,
+            line: 1,
+            column: 6,
+            file: 0,
+        };
+
+        let result: Result<()> = Err(anyhow::anyhow!("Outer"));
+        assert_eq!(error_span(&result.at(span).unwrap_err()), Some(span));
+
+        // The most specific span wins
+        let result: Result<()> = Err(error_at(inner, "Inner"));
+        assert_eq!(error_span(&result.at(span).unwrap_err()), Some(inner));
+    }
+}
@@ -1,11 +1,14 @@
+use std::fmt;
 use std::iter::Peekable;
+use std::rc::Rc;
 
 use crate::assembler::AsmTokenIter;
+use crate::diagnostic::{Span, error_at};
 use crate::{
     TokenIter,
     tokens::{Register, Token},
 };
-use anyhow::{Context, Result, anyhow, bail};
+use anyhow::{Context, Result};
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum BinaryOp {
@@ -13,15 +16,59 @@ pub enum BinaryOp {
     Sub,
     Mul,
     Div,
+    Mod,
     Xor,
+    And,
+    Or,
+    Shl,
+    Shr,
+    LogicalAnd,
+    LogicalOr,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Associativity {
+    Left,
+    /// No operator is right associative yet but the parser supports it
+    #[allow(dead_code)]
+    Right,
 }
 
 impl BinaryOp {
+    /// Every binary operator is left associative like in C, so `a - b - c` is `(a - b) - c`
+    pub fn associativity(&self) -> Associativity {
+        Associativity::Left
+    }
+
+    /// Returns the (left, right) binding power of the operator for the Pratt parser. The operator
+    /// binds to the side with the higher power, which is how associativity is expressed
+    fn binding_power(&self) -> (u8, u8) {
+        let power = self.precedence() * 2;
+        match self.associativity() {
+            Associativity::Left => (power, power + 1),
+            Associativity::Right => (power + 1, power),
+        }
+    }
+
+    /// The same precedence levels as C, a higher precedence binds tighter
     pub fn precedence(&self) -> u8 {
         match self {
-            BinaryOp::Xor => 1,
-            BinaryOp::Add | BinaryOp::Sub => 2,
-            BinaryOp::Mul | BinaryOp::Div => 3,
+            BinaryOp::LogicalOr => 1,
+            BinaryOp::LogicalAnd => 2,
+            BinaryOp::Or => 3,
+            BinaryOp::Xor => 4,
+            BinaryOp::And => 5,
+            BinaryOp::Eq | BinaryOp::Ne => 6,
+            BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => 7,
+            BinaryOp::Shl | BinaryOp::Shr => 8,
+            BinaryOp::Add | BinaryOp::Sub => 9,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => 10,
         }
     }
 
@@ -33,7 +80,18 @@ impl BinaryOp {
 
     /// Returns a tuple containing the result of the calculation, and a booleon of whether the
     /// operation would result in signed overflow
+    ///
+    /// Comparisons and logical operators evaluate to 1 or 0. Shifts are logical and shifting by
+    /// 64 or more is treated as an overflow. Division by zero results in 0 and an overflow,
+    /// callers are expected to report it before calculating
     pub fn overflowing_calculate(&self, lhs: u64, rhs: u64) -> (u64, bool) {
+        match self {
+            BinaryOp::Shl => return (lhs.checked_shl(rhs as u32).unwrap_or(0), rhs >= 64),
+            BinaryOp::Shr => return (lhs.checked_shr(rhs as u32).unwrap_or(0), rhs >= 64),
+            BinaryOp::Div | BinaryOp::Mod if rhs == 0 => return (0, true),
+            _ => {}
+        }
+
         // Do the operations as if it was signed
         let lhs = lhs as i64;
         let rhs = rhs as i64;
@@ -42,10 +100,28 @@ impl BinaryOp {
             BinaryOp::Sub => lhs.overflowing_sub(rhs),
             BinaryOp::Mul => lhs.overflowing_mul(rhs),
             BinaryOp::Div => lhs.overflowing_div(rhs),
+            BinaryOp::Mod => lhs.overflowing_rem(rhs),
             BinaryOp::Xor => (lhs ^ rhs, false),
+            BinaryOp::And => (lhs & rhs, false),
+            BinaryOp::Or => (lhs | rhs, false),
+            BinaryOp::LogicalAnd => ((lhs != 0 && rhs != 0) as i64, false),
+            BinaryOp::LogicalOr => ((lhs != 0 || rhs != 0) as i64, false),
+            BinaryOp::Eq => ((lhs == rhs) as i64, false),
+            BinaryOp::Ne => ((lhs != rhs) as i64, false),
+            BinaryOp::Lt => ((lhs < rhs) as i64, false),
+            BinaryOp::Le => ((lhs <= rhs) as i64, false),
+            BinaryOp::Gt => ((lhs > rhs) as i64, false),
+            BinaryOp::Ge => ((lhs >= rhs) as i64, false),
+            BinaryOp::Shl | BinaryOp::Shr => unreachable!("Shifts are handled above"),
         };
         (result as u64, overflow)
     }
+
+    /// Whether the operation can be applied to a relocatable value, such as a label or a symbol
+    /// whose value isn't known until link time
+    pub fn is_relocatable(&self) -> bool {
+        matches!(self, BinaryOp::Add | BinaryOp::Sub)
+    }
 }
 
 impl TryFrom<&Token> for BinaryOp {
@@ -57,7 +133,20 @@ impl TryFrom<&Token> for BinaryOp {
             Token::Sub => Ok(Sub),
             Token::Mul => Ok(Mul),
             Token::Div => Ok(Div),
+            Token::Percent => Ok(Mod),
             Token::Caret => Ok(Xor),
+            Token::Ampersand => Ok(And),
+            Token::Pipe => Ok(Or),
+            Token::ShiftLeft => Ok(Shl),
+            Token::ShiftRight => Ok(Shr),
+            Token::DoubleAmpersand => Ok(LogicalAnd),
+            Token::DoublePipe => Ok(LogicalOr),
+            Token::DoubleEqual => Ok(Eq),
+            Token::NotEqual => Ok(Ne),
+            Token::Less => Ok(Lt),
+            Token::LessEqual => Ok(Le),
+            Token::Greater => Ok(Gt),
+            Token::GreaterEqual => Ok(Ge),
             _ => Err(()),
         }
     }
@@ -66,12 +155,20 @@ impl TryFrom<&Token> for BinaryOp {
 #[derive(Debug, Clone)]
 pub enum UnaryOp {
     Neg,
+    /// Bitwise not
+    Not,
+    LogicalNot,
 }
 
 impl UnaryOp {
+    /// Prefix operators bind tighter than every binary operator, so `-a * b` is `(-a) * b`
+    const BINDING_POWER: u8 = u8::MAX;
+
     pub fn calculate(&self, val: u64) -> u64 {
         match self {
             UnaryOp::Neg => val.wrapping_neg(),
+            UnaryOp::Not => !val,
+            UnaryOp::LogicalNot => (val == 0) as u64,
         }
     }
 }
@@ -82,6 +179,8 @@ impl TryFrom<&Token> for UnaryOp {
         use UnaryOp::*;
         match value {
             Token::Sub => Ok(Neg),
+            Token::Tilde => Ok(Not),
+            Token::Exclamation => Ok(LogicalNot),
             _ => Err(()),
         }
     }
@@ -94,6 +193,92 @@ impl TryFrom<Token> for UnaryOp {
     }
 }
 
+/// Functions that can be called from within an expression
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Builtin {
+    /// `defined(symbol)` is 1 if `symbol` has been defined so far, and 0 otherwise
+    Defined,
+    /// `sizeof(.section)` is the size of the section at the point the expression is evaluated
+    Sizeof,
+    /// `alignof(.section)` is the largest alignment the section requires
+    Alignof,
+    /// `align_up(x, n)` rounds `x` up to the next multiple of `n`
+    AlignUp,
+    /// `lo32(x)` is the low 32 bits of `x`
+    Lo32,
+    /// `hi32(x)` is the high 32 bits of `x`
+    Hi32,
+    /// `min(a, b)` is the signed minimum of `a` and `b`
+    Min,
+    /// `max(a, b)` is the signed maximum of `a` and `b`
+    Max,
+    /// `log2(x)` is the base 2 logarithm of `x` rounded down
+    Log2,
+    /// `strlen("...")` is the length of the string in bytes after escapes are decoded
+    Strlen,
+}
+
+impl Builtin {
+    pub fn from_name(name: &str) -> Option<Self> {
+        let builtin = match name {
+            "defined" => Builtin::Defined,
+            "sizeof" => Builtin::Sizeof,
+            "alignof" => Builtin::Alignof,
+            "align_up" => Builtin::AlignUp,
+            "lo32" => Builtin::Lo32,
+            "hi32" => Builtin::Hi32,
+            "min" => Builtin::Min,
+            "max" => Builtin::Max,
+            "log2" => Builtin::Log2,
+            "strlen" => Builtin::Strlen,
+            _ => return None,
+        };
+        Some(builtin)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Builtin::Defined => "defined",
+            Builtin::Sizeof => "sizeof",
+            Builtin::Alignof => "alignof",
+            Builtin::AlignUp => "align_up",
+            Builtin::Lo32 => "lo32",
+            Builtin::Hi32 => "hi32",
+            Builtin::Min => "min",
+            Builtin::Max => "max",
+            Builtin::Log2 => "log2",
+            Builtin::Strlen => "strlen",
+        }
+    }
+
+    /// Whether the argument is the name of a section instead of an expression
+    pub fn takes_section_name(&self) -> bool {
+        matches!(self, Builtin::Sizeof | Builtin::Alignof)
+    }
+
+    fn arg_count(&self) -> usize {
+        match self {
+            Builtin::AlignUp | Builtin::Min | Builtin::Max => 2,
+            _ => 1,
+        }
+    }
+}
+
+impl fmt::Display for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// An argument to a builtin function
+#[derive(Debug, Clone)]
+pub enum Argument {
+    Expr(Box<Node>),
+    /// The name of a symbol or section
+    Name(String),
+    String(Rc<[u8]>),
+}
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum Mode {
     None,
@@ -116,102 +301,332 @@ pub enum Node {
         expr: Box<Self>,
     },
     Expression(Box<Self>),
+    Call {
+        function: Builtin,
+        args: Vec<Argument>,
+    },
+}
+
+impl Node {
+    /// Calls `f` with the name of every symbol the expression refers to
+    pub fn for_each_identifier(&self, f: &mut impl FnMut(&str)) {
+        match self {
+            Node::Constant(_) | Node::Register(_) => {}
+            Node::Identifier(id) => f(id),
+            Node::BinaryOp { left, right, .. } => {
+                left.for_each_identifier(f);
+                right.for_each_identifier(f);
+            }
+            Node::UnaryOp { expr, .. } | Node::Expression(expr) => expr.for_each_identifier(f),
+            Node::Call { args, .. } => {
+                for arg in args {
+                    if let Argument::Expr(expr) = arg {
+                        expr.for_each_identifier(f);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Replaces every symbol that `value` returns a node for with that node
+    pub fn substitute(&mut self, value: &impl Fn(&str) -> Option<Node>) {
+        match self {
+            Node::Constant(_) | Node::Register(_) => {}
+            Node::Identifier(id) => {
+                if let Some(value) = value(id) {
+                    *self = value;
+                }
+            }
+            Node::BinaryOp { left, right, .. } => {
+                left.substitute(value);
+                right.substitute(value);
+            }
+            Node::UnaryOp { expr, .. } | Node::Expression(expr) => expr.substitute(value),
+            Node::Call { args, .. } => {
+                for arg in args {
+                    if let Argument::Expr(expr) = arg {
+                        expr.substitute(value);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Parses an expression and also returns the span of source code the expression was parsed from
+pub fn parse_spanned_expr<'a>(
+    tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+) -> Result<(Box<Node>, Span)> {
+    let (expr, span) = parse_nested_expr(tokens)?;
+
+    if let Some(token) = tokens.peek()
+        && matches!(token.token, Token::RBrace)
+    {
+        return Err(error_at(token.span, "Unmatched closing brace"));
+    }
+
+    Ok((expr, span))
 }
 
-pub fn parse_expr<'a>(tokens: &mut Peekable<impl AsmTokenIter<'a>>) -> Result<Box<Node>> {
-    let mut left = parse_constant(tokens)?;
+/// Parses an expression that can be followed by the closing brace of an enclosing expression or
+/// function call
+fn parse_nested_expr<'a>(
+    tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+) -> Result<(Box<Node>, Span)> {
+    let mut span = tokens.peek().map(|token| token.span).unwrap_or_default();
+    let expr = parse_binary(tokens, 0, &mut span)?;
+    Ok((expr, span))
+}
+
+/// Parses operands and binary operators for as long as the operators bind tighter than
+/// `min_power`. `span` is extended to cover every token consumed
+fn parse_binary<'a>(
+    tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    min_power: u8,
+    span: &mut Span,
+) -> Result<Box<Node>> {
+    let mut left = parse_constant(tokens, span)?;
 
     while let Some(token) = tokens.peek() {
-        let token = &token.token;
-        let op: BinaryOp = match token.try_into() {
-            Ok(op) => op,
-            Err(_) => break,
+        let Ok(op) = BinaryOp::try_from(&token.token) else {
+            break;
         };
 
-        // Consume the peeked token
-        let _ = tokens
-            .next()
-            .expect("Token was peeked so this should always be Some. This is a bug");
+        let (left_power, right_power) = op.binding_power();
+        if left_power < min_power {
+            break;
+        }
 
-        let right = parse_constant(tokens)?;
+        // Consume the peeked operator
+        let _ = tokens.next();
 
-        insert_into_tree(&mut left, op, right);
+        let right = parse_binary(tokens, right_power, span)?;
+        left = Box::new(Node::BinaryOp { op, left, right });
     }
 
     Ok(left)
 }
 
-fn parse_constant<'a>(tokens: &mut Peekable<impl AsmTokenIter<'a>>) -> Result<Box<Node>> {
-    let node = match &tokens.next().with_context(|| "Expected token")?.token {
-        Token::Number(num) => Node::Constant(*num),
+/// `span` is extended to cover every token consumed
+fn parse_constant<'a>(
+    tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    span: &mut Span,
+) -> Result<Box<Node>> {
+    let token = tokens.next().with_context(|| "Expected token")?;
+    *span = span.to(token.span);
+
+    let node = match &token.token {
+        Token::Number(num) | Token::Character(num, _) => Node::Constant(*num),
         Token::Register(reg) => Node::Register(*reg),
-        Token::Identifier(id) => Node::Identifier(id.clone()),
+        Token::Identifier(id) => match Builtin::from_name(id) {
+            Some(function) if matches!(tokens.peek(), Some(t) if matches!(t.token, Token::LBrace)) => {
+                parse_call(function, tokens, span)?
+            }
+            _ => Node::Identifier(id.clone()),
+        },
         Token::LBrace => {
-            // tokens.next().unwrap().unwrap();
-            let expr = parse_expr(tokens)?;
-            match tokens
-                .next()
-                .with_context(|| "Expected token but found EOF")?
-                .token
-            {
-                Token::RBrace => Node::Expression(expr),
-                _ => return Err(anyhow!("Expected closing brace")),
+            let (expr, inner) = parse_nested_expr(tokens)?;
+            *span = span.to(inner);
+
+            match tokens.next() {
+                Some(closing) if matches!(closing.token, Token::RBrace) => {
+                    *span = span.to(closing.span);
+                    Node::Expression(expr)
+                }
+                Some(closing) if !matches!(closing.token, Token::Newline) => {
+                    return Err(error_at(closing.span, "Expected closing brace"));
+                }
+                // The line ended before the brace was closed, so point at the brace that is
+                // missing its partner
+                _ => return Err(error_at(token.span, "Unclosed brace")),
             }
         }
-        Token::Ascii(_) => bail!("Cannot use strings in an expression"),
+        Token::RBrace => {
+            return Err(error_at(
+                token.span,
+                "Expected expression before closing brace",
+            ));
+        }
+        Token::Newline => {
+            return Err(error_at(
+                token.span,
+                "Expected expression before end of line",
+            ));
+        }
+        Token::Ascii(_) => return Err(error_at(token.span, "Cannot use strings in an expression")),
+        Token::Float(_) => {
+            return Err(error_at(
+                token.span,
+                "Float literals can only be used with .f32 and .f64, not in integer expressions",
+            ));
+        }
         unary_op => match UnaryOp::try_from(unary_op) {
             Ok(op) => Node::UnaryOp {
                 op,
-                expr: parse_constant(tokens)?,
+                expr: parse_binary(tokens, UnaryOp::BINDING_POWER, span)?,
             },
-            _ => return Err(anyhow!("Invalid token while parsing expression")),
+            _ => {
+                return Err(error_at(
+                    token.span,
+                    "Invalid token while parsing expression",
+                ));
+            }
         },
     };
     Ok(Box::new(node))
 }
 
-fn insert_into_tree(left: &mut Box<Node>, op: BinaryOp, right: Box<Node>) {
-    /// Replaces `left` with a new Node::Binary with the operation `op` and the left and right
-    /// fields being the old `left` value and the given `right` value
-    fn insert_as_binary_op(left: &mut Box<Node>, op: BinaryOp, right: Box<Node>) {
-        // Allocate the box here to prevent a panic from happening between the ptr::read and
-        // ptr::write, panicking would cause a double-free and UB
-        let uninit = Box::new_uninit();
-        let op = Node::BinaryOp {
-            op,
-            // SAFETY: the mutable reference `left` is never read after this and no code can panic between here and
-            // when we ptr::write left to prevent double frees and other undefined behavior
-            left: unsafe { std::ptr::read(left) },
-            right,
+/// Parses the argument list of a call to `function`, the opening brace must not have been consumed
+/// yet. `span` is extended to cover every token consumed
+fn parse_call<'a>(
+    function: Builtin,
+    tokens: &mut Peekable<impl AsmTokenIter<'a>>,
+    span: &mut Span,
+) -> Result<Node> {
+    // Consume the opening brace
+    if let Some(token) = tokens.next() {
+        *span = span.to(token.span);
+    }
+
+    let mut args = Vec::with_capacity(function.arg_count());
+    for i in 0..function.arg_count() {
+        if i > 0 {
+            let token = tokens
+                .next()
+                .with_context(|| "Expected token but found EOF")?;
+            *span = span.to(token.span);
+            if !matches!(token.token, Token::Comma) {
+                return Err(error_at(
+                    token.span,
+                    format!(
+                        "Expected comma, {function} takes {} arguments",
+                        function.arg_count()
+                    ),
+                ));
+            }
+        }
+
+        let arg = match function {
+            Builtin::Defined | Builtin::Sizeof | Builtin::Alignof => {
+                let token = tokens
+                    .next()
+                    .with_context(|| "Expected token but found EOF")?;
+                *span = span.to(token.span);
+                match &token.token {
+                    Token::Identifier(name) => Argument::Name(name.clone()),
+                    _ if function == Builtin::Defined => {
+                        return Err(error_at(token.span, "Expected a symbol name"));
+                    }
+                    _ => return Err(error_at(token.span, "Expected a section name")),
+                }
+            }
+            Builtin::Strlen => {
+                let token = tokens
+                    .next()
+                    .with_context(|| "Expected token but found EOF")?;
+                *span = span.to(token.span);
+                match &token.token {
+                    Token::Ascii(string) | Token::Character(_, string) => {
+                        Argument::String(string.clone())
+                    }
+                    _ => return Err(error_at(token.span, "Expected a string")),
+                }
+            }
+            _ => {
+                let (expr, inner) = parse_nested_expr(tokens)?;
+                *span = span.to(inner);
+                Argument::Expr(expr)
+            }
         };
-        // SAFETY: `left` is a reference so its garunteed to be a safe pointer
-        // We need to use ptr::write because `left` has already been 'moved' with `ptr::read`.
-        // Writing normally would cause the old contents, which `left` doesn't own, to be dropped
-        unsafe { std::ptr::write(left, Box::write(uninit, op)) };
-    }
-
-    match &mut **left {
-        // These expression nodes are always insertion points for any operator
-        Node::Constant(_)
-        | Node::Register(_)
-        | Node::Identifier(_)
-        | Node::Expression(_)
-        | Node::UnaryOp { .. } => {
-            insert_as_binary_op(left, op, right);
-        }
-        Node::BinaryOp {
-            op: op2,
-            left: _,
-            right: right2,
-        } => {
-            if op.precedence() > op2.precedence() {
-                // Precedence is greater so we descend further down the tree to find the right spot
-                // for this operation to go
-                insert_into_tree(right2, op, right);
-            } else {
-                // Precdence is less than or equal to the operator attemping to be inserted
-                insert_as_binary_op(left, op, right);
+        args.push(arg);
+    }
+
+    let closing = tokens
+        .next()
+        .with_context(|| "Expected token but found EOF")?;
+    *span = span.to(closing.span);
+    if !matches!(closing.token, Token::RBrace) {
+        return Err(error_at(
+            closing.span,
+            format!(
+                "Expected closing brace, {function} takes {} argument{}",
+                function.arg_count(),
+                if function.arg_count() == 1 { "" } else { "s" }
+            ),
+        ));
+    }
+
+    Ok(Node::Call { function, args })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::diagnostic::error_span;
+
+    /// Parses `source` and prints the tree with every operation wrapped in parentheses
+    fn parse(source: &str) -> Result<String> {
+        fn print(node: &Node) -> String {
+            match node {
+                Node::Constant(value) => value.to_string(),
+                Node::Register(register) => format!("{register:?}"),
+                Node::Identifier(id) => id.clone(),
+                Node::BinaryOp { op, left, right } => {
+                    format!("({} {op:?} {})", print(left), print(right))
+                }
+                Node::UnaryOp { op, expr } => format!("({op:?} {})", print(expr)),
+                Node::Expression(expr) => print(expr),
+                Node::Call { function, args } => format!("{function}({})", args.len()),
             }
         }
+
+        let tokens = Assembler::tokenize(source).expect("Source should tokenize");
+        let (expr, _) = parse_spanned_expr(&mut tokens.iter().peekable())?;
+        Ok(print(&expr))
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(parse("1 + 2 * 3").unwrap(), "(1 Add (2 Mul 3))");
+        assert_eq!(parse("1 * 2 + 3").unwrap(), "((1 Mul 2) Add 3)");
+        assert_eq!(parse("1 - 2 - 3").unwrap(), "((1 Sub 2) Sub 3)");
+        assert_eq!(parse("1 - (2 - 3)").unwrap(), "(1 Sub (2 Sub 3))");
+        assert_eq!(
+            parse("a || b && c | d ^ e & f == g < h << i + j * k").unwrap(),
+            "(a LogicalOr (b LogicalAnd (c Or (d Xor (e And (f Eq (g Lt (h Shl (i Add (j Mul k))))))))))"
+        );
+        assert_eq!(
+            parse("a * b + c << d < e == f & g ^ h | i && j || k").unwrap(),
+            "((((((((((a Mul b) Add c) Shl d) Lt e) Eq f) And g) Xor h) Or i) LogicalAnd j) LogicalOr k)"
+        );
+    }
+
+    #[test]
+    fn test_unary() {
+        assert_eq!(parse("-a * b").unwrap(), "((Neg a) Mul b)");
+        assert_eq!(parse("a * -b").unwrap(), "(a Mul (Neg b))");
+        assert_eq!(parse("~-a + 1").unwrap(), "((Not (Neg a)) Add 1)");
+        assert_eq!(parse("!(a == b)").unwrap(), "(LogicalNot (a Eq b))");
+        assert_eq!(parse("a - -1").unwrap(), "(a Sub (Neg 1))");
+    }
+
+    #[test]
+    fn test_braces() {
+        assert_eq!(parse("((1))").unwrap(), "1");
+        assert_eq!(parse("max(1 + 2, (3))").unwrap(), "max(2)");
+
+        let error = parse("(1 + (2 * 3)").unwrap_err();
+        assert_eq!(error.to_string(), "Unclosed brace");
+        assert_eq!(error_span(&error).unwrap().column, 1);
+
+        let error = parse("1 + 2)").unwrap_err();
+        assert_eq!(error.to_string(), "Unmatched closing brace");
+        assert_eq!(error_span(&error).unwrap().column, 6);
+
+        let error = parse("(1 + )").unwrap_err();
+        assert_eq!(error_span(&error).unwrap().column, 6);
+
+        assert!(parse("(1 2)").is_err());
     }
 }
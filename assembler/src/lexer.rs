@@ -1,16 +1,58 @@
-use crate::tokens::TokenIter;
+use crate::diagnostic::Span;
 
 #[derive(Debug)]
 pub struct Lexer<'a> {
     source: &'a str,
     current: usize,
+
+    /// The line number of the byte at `scanned`
+    line: usize,
+    /// The byte offset of the first character of `line`
+    line_start: usize,
+    /// Everything before this byte offset has already been counted into `line` and `line_start`
+    scanned: usize,
+    /// The file `source` comes from, see `Span::file`
+    file: usize,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(source: &'a str) -> Self {
+        Self::for_file(source, 0)
+    }
+
+    /// Creates a lexer whose spans point into `file`
+    pub fn for_file(source: &'a str, file: usize) -> Self {
         Self {
-            source: source,
+            source,
             current: 0,
+            line: 1,
+            line_start: 0,
+            scanned: 0,
+            file,
+        }
+    }
+
+    /// Creates the span for the token `source[start..end]`.
+    ///
+    /// Tokens are always returned in order so the line and column are computed incrementally
+    /// from the end of the last scan instead of from the start of the source
+    fn span(&mut self, start: usize, end: usize) -> Span {
+        for (i, ch) in self.source[self.scanned..start].char_indices() {
+            if ch == '\n' {
+                self.line += 1;
+                self.line_start = self.scanned + i + 1;
+            }
+        }
+        self.scanned = start;
+
+        let column = self.source[self.line_start..start].chars().count() + 1;
+
+        Span {
+            start,
+            end,
+            line: self.line,
+            column,
+            file: self.file,
         }
     }
 
@@ -29,15 +71,203 @@ impl<'a> Lexer<'a> {
                 | '/'
                 | '^'
                 | '&'
+                | '|'
+                | '%'
+                | '~'
+                | '!'
+                | '<'
+                | '>'
                 | '@'
                 | ':'
                 | '$'
         )
     }
+
+    /// The sign of the exponent in a float literal like `1.5e-3` is part of the literal instead
+    /// of being an operator. Hexadecimal, binary and octal numbers have no exponent
+    fn is_exponent_sign(before: &str, ch: char, next: Option<&(usize, char)>) -> bool {
+        let Some(mantissa) = before.strip_suffix(['e', 'E']) else {
+            return false;
+        };
+
+        matches!(ch, '+' | '-')
+            && matches!(next, Some((_, next)) if next.is_ascii_digit())
+            && mantissa.starts_with(|ch: char| ch.is_ascii_digit())
+            && mantissa
+                .chars()
+                .all(|ch| ch.is_ascii_digit() || ch == '_' || ch == '.')
+    }
+
+    /// Operators that are made up of two seperator chars and are lexed as one token
+    fn is_double_char_operator(first: char, second: char) -> bool {
+        matches!(
+            (first, second),
+            ('<', '<')
+                | ('>', '>')
+                | ('<', '=')
+                | ('>', '=')
+                | ('=', '=')
+                | ('!', '=')
+                | ('&', '&')
+                | ('|', '|')
+        )
+    }
+}
+
+/// A character inside of a string or character literal after its escape sequence was decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unescaped {
+    /// A raw byte written with a `\x` or octal escape
+    Byte(u8),
+    Char(char),
+}
+
+/// An invalid escape sequence. `start` and `end` are the byte offsets of the escape sequence in
+/// the text passed to `unescape`
+#[derive(Debug)]
+pub struct EscapeError {
+    pub start: usize,
+    pub end: usize,
+    pub message: String,
+}
+
+/// Decodes every character and escape sequence in the contents of a string or character literal
+/// (without the surrounding quotes).
+///
+/// Supports the C escapes `\n \r \t \a \b \f \v \\ \' \"`, one to three digit octal escapes
+/// like `\0` or `\177`, `\xHH` with exactly two hex digits, and `\u{...}` Unicode escapes
+pub fn unescape(text: &str) -> Result<Vec<Unescaped>, EscapeError> {
+    let mut decoded = Vec::with_capacity(text.len());
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((start, ch)) = chars.next() {
+        if ch != '\\' {
+            decoded.push(Unescaped::Char(ch));
+            continue;
+        }
+
+        let error = |end: usize, message: String| EscapeError {
+            start,
+            end,
+            message,
+        };
+
+        let Some((i, escape)) = chars.next() else {
+            return Err(error(
+                text.len(),
+                "'\\' must be followed by an escape character".to_string(),
+            ));
+        };
+        let mut end = i + escape.len_utf8();
+
+        let value = match escape {
+            'n' => Unescaped::Char('\n'),
+            'r' => Unescaped::Char('\r'),
+            't' => Unescaped::Char('\t'),
+            'a' => Unescaped::Char('\x07'),
+            'b' => Unescaped::Char('\x08'),
+            'f' => Unescaped::Char('\x0c'),
+            'v' => Unescaped::Char('\x0b'),
+            '\\' | '\'' | '"' => Unescaped::Char(escape),
+            '0'..='7' => {
+                let mut value = escape.to_digit(8).unwrap();
+                for _ in 0..2 {
+                    let Some((i, digit)) = chars.next_if(|(_, ch)| ch.is_digit(8)) else {
+                        break;
+                    };
+                    value = value * 8 + digit.to_digit(8).unwrap();
+                    end = i + 1;
+                }
+
+                match u8::try_from(value) {
+                    Ok(byte) => Unescaped::Byte(byte),
+                    Err(_) => {
+                        return Err(error(
+                            end,
+                            format!(
+                                "Octal escape '{}' is larger than '\\377'",
+                                &text[start..end]
+                            ),
+                        ));
+                    }
+                }
+            }
+            'x' => {
+                let mut value = 0;
+                for _ in 0..2 {
+                    let Some((i, digit)) = chars.next_if(|(_, ch)| ch.is_ascii_hexdigit()) else {
+                        return Err(error(
+                            end,
+                            "'\\x' must be followed by exactly two hex digits".to_string(),
+                        ));
+                    };
+                    value = value * 16 + digit.to_digit(16).unwrap() as u8;
+                    end = i + 1;
+                }
+
+                Unescaped::Byte(value)
+            }
+            'u' => {
+                if chars.next_if(|(_, ch)| *ch == '{').is_none() {
+                    return Err(error(
+                        end,
+                        "'\\u' must be followed by a code point in braces such as '\\u{1F600}'"
+                            .to_string(),
+                    ));
+                }
+
+                let digits_start = end + 1;
+                let mut closed = false;
+                for (i, ch) in chars.by_ref() {
+                    end = i + ch.len_utf8();
+                    if ch == '}' {
+                        closed = true;
+                        break;
+                    }
+                }
+
+                if !closed {
+                    return Err(error(end, "Unterminated '\\u{' escape".to_string()));
+                }
+
+                let digits = &text[digits_start..end - 1];
+                if digits.is_empty()
+                    || digits.len() > 6
+                    || !digits.chars().all(|ch| ch.is_ascii_hexdigit())
+                {
+                    return Err(error(
+                        end,
+                        "'\\u{...}' must contain between one and six hex digits".to_string(),
+                    ));
+                }
+
+                let code_point = u32::from_str_radix(digits, 16).unwrap();
+                match char::from_u32(code_point) {
+                    Some(ch) => Unescaped::Char(ch),
+                    None => {
+                        return Err(error(
+                            end,
+                            format!("'{}' is not a valid Unicode code point", &text[start..end]),
+                        ));
+                    }
+                }
+            }
+            _ => {
+                return Err(error(
+                    end,
+                    format!("Invalid escape sequence '{}'", &text[start..end]),
+                ));
+            }
+        };
+
+        decoded.push(value);
+    }
+
+    Ok(decoded)
 }
 
 impl<'a> Iterator for Lexer<'a> {
-    type Item = &'a str;
+    type Item = (&'a str, Span);
     /// Parsing logic that uses the output of `next` must take into consideration that `next` may
     /// return None before a final newline if the source code being lexed does not end in a
     /// newline.
@@ -51,8 +281,18 @@ impl<'a> Iterator for Lexer<'a> {
             .map(|(i, ch)| (start_index + i, ch))
             .peekable();
 
+        let mut previous = None;
         while let Some((i, ch)) = iter.next() {
-            if Self::is_seperator_char(ch) {
+            // `\@` is the unique number of a macro expansion and is part of the identifier it is in
+            if ch == '@' && previous == Some('\\') {
+                previous = Some(ch);
+                continue;
+            }
+            previous = Some(ch);
+
+            if Self::is_seperator_char(ch)
+                && !Self::is_exponent_sign(&self.source[self.current..i], ch, iter.peek())
+            {
                 // The current token is everything before the seperator char
                 if self.current != i {
                     final_index = i;
@@ -61,6 +301,11 @@ impl<'a> Iterator for Lexer<'a> {
                 // The current token is the seperator char
                 else {
                     final_index = i + 1;
+                    if let Some((_, next)) = iter.peek()
+                        && Self::is_double_char_operator(ch, *next)
+                    {
+                        final_index = i + 2;
+                    }
                     break;
                 }
             } else if ch.is_whitespace() {
@@ -77,15 +322,21 @@ impl<'a> Iterator for Lexer<'a> {
                 }
 
                 let opening_quote_type = ch;
+                final_index = i + ch.len_utf8();
 
                 while let Some((i, ch)) = iter.next() {
-                    final_index = i;
-                    if ch == opening_quote_type {
+                    final_index = i + ch.len_utf8();
+                    if ch == '\\' {
+                        // An escaped character never ends the string, it is decoded later by
+                        // `unescape`
+                        if let Some((i, ch)) = iter.next() {
+                            final_index = i + ch.len_utf8();
+                        }
+                    } else if ch == opening_quote_type {
                         break;
                     }
                 }
 
-                final_index += 1;
                 break;
             } else if ch == ';' {
                 // Return the token before the comment
@@ -119,15 +370,15 @@ impl<'a> Iterator for Lexer<'a> {
         if self.current < self.source.len() {
             // If this is true then we have reached the end of the source code so we return
             // everything from self.current to source.len()
-            if self.current >= final_index {
-                let token = &self.source[self.current..];
-                self.current = self.source.len();
-                Some(token)
+            let start = self.current;
+            let end = if self.current >= final_index {
+                self.source.len()
             } else {
-                let token = &self.source[self.current..final_index];
-                self.current = final_index;
-                Some(token)
-            }
+                final_index
+            };
+
+            self.current = end;
+            Some((&self.source[start..end], self.span(start, end)))
         } else {
             None
         }
@@ -139,7 +390,13 @@ mod tests {
     use super::*;
 
     fn lex(source: &str) -> Vec<&str> {
-        Lexer::new(source).collect()
+        Lexer::new(source).map(|(token, _)| token).collect()
+    }
+
+    fn spans(source: &str) -> Vec<(usize, usize)> {
+        Lexer::new(source)
+            .map(|(_, span)| (span.line, span.column))
+            .collect()
     }
 
     #[test]
@@ -160,6 +417,45 @@ mod tests {
         assert_eq!(lexed, &["test", "\n"]);
     }
 
+    #[test]
+    fn test_operators() {
+        let lexed = lex("a<<2|b&&c!=~d>=e%f");
+        assert_eq!(
+            lexed,
+            &[
+                "a", "<<", "2", "|", "b", "&&", "c", "!=", "~", "d", ">=", "e", "%", "f"
+            ]
+        );
+
+        let lexed = lex("a < b > c == d || !e & f >> g <= h");
+        assert_eq!(
+            lexed,
+            &[
+                "a", "<", "b", ">", "c", "==", "d", "||", "!", "e", "&", "f", ">>", "g", "<=", "h"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_float_exponent() {
+        let lexed = lex("1.5e-3, 2E+10, 1e-x");
+        assert_eq!(lexed, &["1.5e-3", ",", "2E+10", ",", "1e", "-", "x"]);
+
+        let lexed = lex("0x1e-3 name-1");
+        assert_eq!(lexed, &["0x1e", "-", "3", "name", "-", "1"]);
+    }
+
+    #[test]
+    fn test_macro_parameters() {
+        let lexed = lex("loop_\\@: add \\reg, \\@ + \\count@x");
+        assert_eq!(
+            lexed,
+            &[
+                "loop_\\@", ":", "add", "\\reg", ",", "\\@", "+", "\\count", "@", "x"
+            ]
+        );
+    }
+
     #[test]
     fn test_comments() {
         let lexed = lex("Test ; This is a comment\n");
@@ -205,4 +501,91 @@ mod tests {
         let lexed = lex("Testing 'single' quote 's'\n");
         assert_eq!(lexed, &["Testing", "'single'", "quote", "'s'", "\n"]);
     }
+
+    #[test]
+    fn test_spans() {
+        let spans = spans("mov r3, [r1 + label]\n  jmp end");
+        assert_eq!(
+            spans,
+            &[
+                (1, 1),
+                (1, 5),
+                (1, 7),
+                (1, 9),
+                (1, 10),
+                (1, 13),
+                (1, 15),
+                (1, 20),
+                (1, 21),
+                (2, 3),
+                (2, 7)
+            ]
+        );
+
+        let mut lexer = Lexer::new("a ; comment\n\"str\"");
+        let (_, span) = lexer.next().unwrap();
+        assert_eq!((span.start, span.end), (0, 1));
+        let (token, span) = lexer.next().unwrap();
+        assert_eq!(token, "\n");
+        assert_eq!((span.start, span.line, span.column), (11, 1, 12));
+        let (_, span) = lexer.next().unwrap();
+        assert_eq!(
+            (span.start, span.end, span.line, span.column),
+            (12, 17, 2, 1)
+        );
+    }
+
+    #[test]
+    fn test_escaped_quotes() {
+        let lexed = lex(".ascii \"say \\\"hi\\\"\", 'it\\'s'\n");
+        assert_eq!(
+            lexed,
+            &[".ascii", "\"say \\\"hi\\\"\"", ",", "'it\\'s'", "\n"]
+        );
+
+        // The escaped backslash doesn't escape the closing quote
+        let lexed = lex("\"\\\\\" after");
+        assert_eq!(lexed, &["\"\\\\\"", "after"]);
+    }
+
+    fn decode(text: &str) -> Vec<Unescaped> {
+        unescape(text).expect("Escape sequences should be valid")
+    }
+
+    fn decode_error(text: &str) -> (usize, usize, String) {
+        let e = unescape(text).expect_err("Escape sequences should be invalid");
+        (e.start, e.end, e.message)
+    }
+
+    #[test]
+    fn test_unescape() {
+        use Unescaped::*;
+
+        assert_eq!(decode("a\\r\\t"), &[Char('a'), Char('\r'), Char('\t')]);
+        assert_eq!(decode("\\\"\\'\\\\"), &[Char('"'), Char('\''), Char('\\')]);
+        assert_eq!(decode("\\x7f\\0"), &[Byte(0x7f), Byte(0)]);
+        assert_eq!(decode("\\101\\0123"), &[Byte(0o101), Byte(0o12), Char('3')]);
+        assert_eq!(decode("\\u{e9}\\u{1F600}"), &[Char('é'), Char('😀')]);
+
+        assert_eq!(decode_error("ab\\q").0, 2);
+        assert_eq!(decode_error("ab\\q").1, 4);
+        assert!(
+            decode_error("\\q")
+                .2
+                .contains("Invalid escape sequence '\\q'")
+        );
+        assert!(decode_error("\\x7").2.contains("exactly two hex digits"));
+        assert!(decode_error("\\xg0").2.contains("exactly two hex digits"));
+        assert!(decode_error("\\400").2.contains("larger than"));
+        assert!(decode_error("\\u41").2.contains("code point in braces"));
+        assert!(decode_error("\\u{}").2.contains("between one and six"));
+        assert!(
+            decode_error("\\u{1234567}")
+                .2
+                .contains("between one and six")
+        );
+        assert!(decode_error("\\u{41").2.contains("Unterminated"));
+        assert!(decode_error("\\u{D800}").2.contains("not a valid Unicode"));
+        assert!(decode_error("abc\\").2.contains("must be followed"));
+    }
 }
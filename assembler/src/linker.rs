@@ -1,18 +1,19 @@
-use anyhow::{Context, Error, Result, anyhow};
+use anyhow::{Context, Error, Result, anyhow, bail};
 use spdlog::debug;
 use std::{
-    collections::{HashMap, hash_map::Entry},
+    collections::{BTreeMap, HashMap, btree_map, hash_map::Entry},
     rc::Rc,
 };
 
 use crate::{
     assembler::{
         Assembler, calculate_disp32_offset,
-        symbol_table::{self, Symbol, SymbolTable, Type},
+        symbol_table::{self, Binding, Symbol, SymbolTable, Type},
     },
-    module::{self, Module},
+    expression::{BinaryOp, Node},
+    module::{self, Common, Module},
     opcode::Relocation,
-    section::{self, Section},
+    section::{self, Section, SectionFlags, nop_padding},
 };
 
 pub enum Instr {
@@ -47,6 +48,9 @@ pub struct Program {
     globals: HashMap<String, Global>,
     /// The final, linked program
     pub linked: Vec<u8>,
+    /// The size of the program once it's loaded. Larger than `linked` if the program ends with
+    /// nobits sections, which are zeroed memory that isn't part of the image
+    pub size: usize,
     /// `section_offset[i][y]` is the offset of the y'th section in the list of sections of the i'th
     /// module in the `modules` array relative to the final linked program
     pub section_offset: Vec<Vec<usize>>,
@@ -55,10 +59,84 @@ pub struct Program {
     pub section_included: Vec<Vec<bool>>,
 }
 
+/// A label at its final address in the linked program
+#[derive(Debug)]
+pub struct LinkedSymbol {
+    pub name: String,
+    /// The file name of the module that defines the symbol
+    pub module: String,
+    pub address: u64,
+    pub type_: Type,
+    pub binding: Binding,
+    pub size: Option<u64>,
+}
+
+impl Program {
+    /// Returns every label in a section that made it into the program, sorted by address. Meant
+    /// for map files and other tools that need to know what's where
+    pub fn symbols(&self) -> Vec<LinkedSymbol> {
+        let mut symbols: Vec<_> = self
+            .modules
+            .iter()
+            .enumerate()
+            .flat_map(|(module_idx, module)| {
+                module.symbols.iter().filter_map(move |(name, symbol)| {
+                    let section = symbol.section_index?;
+                    if !self.section_included[module_idx][section] {
+                        return None;
+                    }
+
+                    Some(LinkedSymbol {
+                        name: name.to_string(),
+                        module: module.filename.clone(),
+                        address: self.section_offset[module_idx][section] as u64 + symbol.value,
+                        type_: symbol.type_,
+                        binding: symbol.binding,
+                        size: symbol.size,
+                    })
+                })
+            })
+            // Common symbols are the only objects outside of a section
+            .chain(self.globals.iter().filter_map(|(name, global)| {
+                let symbol = global.symbol;
+                (symbol.section_index.is_none() && symbol.type_ == Type::Object).then(|| {
+                    LinkedSymbol {
+                        name: name.clone(),
+                        module: self.modules[global.module].filename.clone(),
+                        address: symbol.value,
+                        type_: symbol.type_,
+                        binding: symbol.binding,
+                        size: symbol.size,
+                    }
+                })
+            }))
+            .collect();
+
+        symbols.sort_by(|a, b| (a.address, &a.name).cmp(&(b.address, &b.name)));
+        symbols
+    }
+
+    /// Formats `symbols` as a map file with one symbol per line
+    pub fn symbol_map(&self) -> String {
+        let mut map = String::from("Address            Size      Type      Binding Symbol\n");
+        for symbol in self.symbols() {
+            let size = symbol
+                .size
+                .map_or(String::from("-"), |size| size.to_string());
+            map.push_str(&format!(
+                "{:#018x} {size:<9} {:<9} {:<7} {} ({})\n",
+                symbol.address, symbol.type_, symbol.binding, symbol.name, symbol.module
+            ));
+        }
+        map
+    }
+}
+
 pub fn link(modules: Vec<Module>, script: Vec<Instr>) -> Result<Program, ()> {
     let mut failed = false;
 
     let mut linked: Vec<u8> = Vec::new();
+    let mut size = 0;
     let mut globals: HashMap<String, Global> = HashMap::new();
     let mut section_offset: Vec<Vec<usize>> = vec![Vec::new(); modules.len()];
     let mut section_included: Vec<Vec<bool>> = vec![Vec::new(); modules.len()];
@@ -76,7 +154,20 @@ pub fn link(modules: Vec<Module>, script: Vec<Instr>) -> Result<Program, ()> {
                 module: module_idx,
                 symbol,
             };
-            globals.insert(global.clone(), symbol);
+            match globals.entry(global.clone()) {
+                Entry::Vacant(entry) => {
+                    entry.insert(symbol);
+                }
+                // A weak symbol never replaces a global one, but a global one always replaces a
+                // weak one
+                Entry::Occupied(mut entry) => {
+                    if symbol.symbol.binding != Binding::Weak
+                        || entry.get().symbol.binding == Binding::Weak
+                    {
+                        entry.insert(symbol);
+                    }
+                }
+            }
         }
     }
 
@@ -104,30 +195,28 @@ pub fn link(modules: Vec<Module>, script: Vec<Instr>) -> Result<Program, ()> {
                 if section == "*" {
                     for (_, value) in section_map.iter() {
                         for (module_idx, section_idx) in value.iter() {
-                            let alignment = modules[*module_idx].sections[*section_idx].alignment;
-                            add_section(
+                            failed |= !add_section(
                                 &mut linked,
+                                &mut size,
                                 &mut section_offset.as_mut_slice(),
                                 section_included.as_mut_slice(),
                                 &modules,
                                 *module_idx,
                                 *section_idx,
-                                alignment,
                             );
                         }
                     }
                 } else {
                     if let Some(sections) = section_map.get(section.as_str()) {
                         for (module_idx, section_idx) in sections.iter() {
-                            let alignment = modules[*module_idx].sections[*section_idx].alignment;
-                            add_section(
+                            failed |= !add_section(
                                 &mut linked,
+                                &mut size,
                                 section_offset.as_mut_slice(),
                                 section_included.as_mut_slice(),
                                 &modules,
                                 *module_idx,
                                 *section_idx,
-                                alignment,
                             );
                         }
                     }
@@ -138,6 +227,8 @@ pub fn link(modules: Vec<Module>, script: Vec<Instr>) -> Result<Program, ()> {
         }
     }
 
+    allocate_commons(&modules, &mut globals, &mut size);
+
     for (module_idx, module) in modules.iter().enumerate() {
         for relocation in module.relocations.iter() {
             let section_name = &module.sections[relocation.section].name;
@@ -146,7 +237,11 @@ pub fn link(modules: Vec<Module>, script: Vec<Instr>) -> Result<Program, ()> {
 
             let (value, symbol_section) = if relocation.symbol.is_empty() {
                 (relocation.addend, None)
-            } else if let Some(symbol) = module.symbols.get_symbol(&relocation.symbol) {
+            } else if let Some(symbol) = module.symbols.get_symbol(&relocation.symbol)
+                // A weak definition might be overridden by another module, which is only known
+                // to the global symbol table
+                && symbol.binding != Binding::Weak
+            {
                 let value = if let Some(section) = symbol.section_index {
                     // TODO: Handle the case where the section won't be included in the final
                     // program
@@ -171,6 +266,8 @@ pub fn link(modules: Vec<Module>, script: Vec<Instr>) -> Result<Program, ()> {
                     global.symbol.value.wrapping_add(relocation.addend)
                 };
                 (value, global.symbol.section_index)
+            } else if module.weak_references.contains(&relocation.symbol) {
+                (relocation.addend, None)
             } else {
                 linker_error(
                     &mut failed,
@@ -320,12 +417,31 @@ pub fn link(modules: Vec<Module>, script: Vec<Instr>) -> Result<Program, ()> {
         }
     }
 
+    for (module_idx, module) in modules.iter().enumerate() {
+        for assertion in module.assertions.iter() {
+            match evaluate_linked(
+                &assertion.expr,
+                &modules,
+                &globals,
+                &section_offset,
+                module_idx,
+                assertion.location,
+            ) {
+                Ok(0) => println!("{}", assertion.diagnostic),
+                Ok(_) => continue,
+                Err(e) => println!("{e} when checking\n{}", assertion.diagnostic),
+            }
+            failed = true;
+        }
+    }
+
     if !failed {
         Ok(Program {
             // Initialize `modules` with a filler for now to prevent issues with the borrow checker
             modules,
             globals,
             linked,
+            size,
             section_offset,
             section_included,
         })
@@ -334,22 +450,128 @@ pub fn link(modules: Vec<Module>, script: Vec<Instr>) -> Result<Program, ()> {
     }
 }
 
+/// Merges the common symbols of every module, using the largest size and the strictest alignment,
+/// and allocates them in zeroed memory after the last placed section. A definition of the same
+/// name in any module takes the place of the common symbol
+fn allocate_commons(modules: &[Module], globals: &mut HashMap<String, Global>, size: &mut usize) {
+    // Sorted by name so the layout doesn't depend on the order of the modules
+    let mut commons: BTreeMap<&str, (usize, Common)> = BTreeMap::new();
+    for (module_idx, module) in modules.iter().enumerate() {
+        for common in module.commons.iter() {
+            if globals.contains_key(&common.name) {
+                continue;
+            }
+
+            match commons.entry(&common.name) {
+                btree_map::Entry::Vacant(entry) => {
+                    entry.insert((module_idx, common.clone()));
+                }
+                btree_map::Entry::Occupied(mut entry) => {
+                    let (_, merged) = entry.get_mut();
+                    merged.size = merged.size.max(common.size);
+                    merged.alignment = merged.alignment.max(common.alignment);
+                }
+            }
+        }
+    }
+
+    for (name, (module, common)) in commons {
+        let alignment: usize = common
+            .alignment
+            .try_into()
+            .expect("u64 doesn't fit in usize");
+        let address = size.next_multiple_of(alignment);
+        *size = address + usize::try_from(common.size).expect("u64 doesn't fit in usize");
+
+        debug!("Allocated common symbol {name} at {address:#x}");
+        let symbol = Symbol {
+            section_index: None,
+            type_: Type::Object,
+            value: address as u64,
+            binding: Binding::Global,
+            size: Some(common.size),
+        };
+        globals.insert(name.to_string(), Global { module, symbol });
+    }
+}
+
+/// Evaluates `expr` from the module with the index `module_idx` using the final address of
+/// every label. `location` is the section and offset of `.` in that module, if it's used
+fn evaluate_linked(
+    expr: &Node,
+    modules: &[Module],
+    globals: &HashMap<String, Global>,
+    section_offset: &[Vec<usize>],
+    module_idx: usize,
+    location: Option<(usize, u64)>,
+) -> Result<u64> {
+    let evaluate =
+        |expr| evaluate_linked(expr, modules, globals, section_offset, module_idx, location);
+
+    let value = match expr {
+        Node::Constant(value) => *value,
+        Node::Identifier(name)
+            if name == "."
+                && let Some((section, offset)) = location =>
+        {
+            (section_offset[module_idx][section] as u64).wrapping_add(offset)
+        }
+        Node::Identifier(name) => {
+            let module = &modules[module_idx];
+            let (module_idx, symbol) = match module.symbols.get_symbol(name) {
+                Some(symbol) if symbol.binding != Binding::Weak => (module_idx, symbol),
+                _ => match globals.get(name) {
+                    Some(global) => (global.module, global.symbol),
+                    None if module.weak_references.contains(name) => return Ok(0),
+                    None => bail!("Undefined symbol '{name}'"),
+                },
+            };
+
+            match symbol.section_index {
+                Some(section) => {
+                    (section_offset[module_idx][section] as u64).wrapping_add(symbol.value)
+                }
+                None => symbol.value,
+            }
+        }
+        Node::BinaryOp { op, left, right } => {
+            let (left, right) = (evaluate(left)?, evaluate(right)?);
+            if matches!(op, BinaryOp::Div | BinaryOp::Mod) && right == 0 {
+                bail!("Division by zero");
+            }
+            op.calculate(left, right)
+        }
+        Node::UnaryOp { op, expr } => op.calculate(evaluate(expr)?),
+        Node::Expression(expr) => evaluate(expr)?,
+        Node::Register(_) => bail!("Invalid use of register"),
+        Node::Call { function, .. } => {
+            bail!("{function} cannot be used in an expression that depends on addresses")
+        }
+    };
+
+    Ok(value)
+}
+
+/// Places a section at the end of the program. `size` is the end of the program including
+/// nobits sections
+///
+/// Returns false if an error was reported while placing it
 fn add_section(
     linked: &mut Vec<u8>,
+    size: &mut usize,
     section_offset: &mut [Vec<usize>],
     section_included: &mut [Vec<bool>],
     modules: &[Module],
     module: usize,
     section: usize,
-    alignment: u64,
-) {
+) -> bool {
     // Skip already included section
     if section_included[module][section] {
         debug!(
             "Section {} in {} was already added",
             modules[module].sections[section].name, modules[module].filename
         );
-        return;
+        return true;
     }
 
     debug!(
@@ -357,18 +579,43 @@ fn add_section(
         modules[module].sections[section].name, modules[module].filename
     );
 
+    let alignment = modules[module].sections[section].alignment;
     let alignment: usize = alignment.try_into().expect("u64 doesn't fit in usize");
-    let padding = (alignment - (linked.len() % alignment)) % alignment;
-
-    linked.resize(linked.len() + padding, 0);
+    let padding = (alignment - (*size % alignment)) % alignment;
 
-    let offset = linked.len();
+    let offset = *size + padding;
 
     section_included[module][section] = true;
     section_offset[module][section] = offset;
 
-    let section = modules[module].sections[section].data.get_ref().as_slice();
-    linked.extend_from_slice(section);
+    let mut failed = false;
+    let section = &modules[module].sections[section];
+    if section.is_nobits() {
+        // Only reserve the address space. It's zero filled if anything is placed after it
+        *size = offset + section.size();
+    } else {
+        // Code is padded with no-ops in case execution falls through into it
+        linked.resize(*size, 0);
+        if section.flags.contains(SectionFlags::EXEC) {
+            match nop_padding(padding) {
+                Some(nops) => linked.extend(nops),
+                None => linker_error(
+                    &mut failed,
+                    &modules[module].filename,
+                    &section.name,
+                    0,
+                    format!(
+                        "{padding} bytes of padding before the section can't be made of no-ops"
+                    ),
+                ),
+            }
+        }
+        linked.resize(offset, 0);
+        linked.extend_from_slice(section.data.get_ref());
+        *size = linked.len();
+    }
+
+    !failed
 }
 
 #[cfg(test)]
@@ -397,4 +644,189 @@ mod tests {
         let linked = link(modules, script).expect("Linking should not fail");
         assert_eq!(linked.linked, &[0x30, 0x3c, 0xab, 0, 0, 0, 0, 0, 0, 0]);
     }
+
+    fn module(filename: &str, source: &str) -> Module {
+        let assembler = Assembler::assemble(String::from(filename), source.to_string())
+            .expect("This should assemble");
+        Module::try_from(assembler).expect("This conversion should not fail")
+    }
+
+    #[test]
+    fn test_weak_symbols() {
+        let library =
+            ".weak handler, hook\n.section .entry\njmp handler\n.u64 hook + 4\nhandler: halt";
+        let script = || {
+            vec![
+                Instr::Section(".entry".to_string()),
+                Instr::Section("*".to_string()),
+            ]
+        };
+
+        // Without an override the weak definition is used and the weak reference is 0
+        let linked =
+            link(vec![module("library.asm", library)], script()).expect("Linking should not fail");
+        assert_eq!(&linked.linked[1..5], &8u32.to_le_bytes());
+        assert_eq!(&linked.linked[5..13], &4u64.to_le_bytes());
+
+        // A global definition in another module takes precedence over the weak one, no matter
+        // the order of the modules
+        let application = ".global handler\n.section .text\nhandler: halt\nhalt";
+        for modules in [
+            vec![
+                module("library.asm", library),
+                module("application.asm", application),
+            ],
+            vec![
+                module("application.asm", application),
+                module("library.asm", library),
+            ],
+        ] {
+            let linked = link(modules, script()).expect("Linking should not fail");
+            // The jump now goes to the start of .text right after .entry
+            assert_eq!(&linked.linked[1..5], &9u32.to_le_bytes());
+            assert_eq!(&linked.linked[5..13], &4u64.to_le_bytes());
+        }
+
+        // A weak definition referenced after it in its own section is overridden too
+        let library = ".weak handler\n.section .entry\nhandler: halt\njmp handler";
+        let modules = vec![
+            module("library.asm", library),
+            module("application.asm", application),
+        ];
+        let linked = link(modules, script()).expect("Linking should not fail");
+        assert_eq!(&linked.linked[2..6], &0u32.to_le_bytes());
+        let linked =
+            link(vec![module("library.asm", library)], script()).expect("Linking should not fail");
+        assert_eq!(&linked.linked[2..6], &(-6i32 as u32).to_le_bytes());
+    }
+
+    #[test]
+    fn test_symbols() {
+        let library = ".global memcpy\n.section .text\n.func memcpy\nhalt\n.endfunc";
+        let application = ".section .entry\nstart: jmp memcpy\n.section .unused\nunused:";
+        let modules = vec![
+            module("library.asm", library),
+            module("application.asm", application),
+        ];
+        let script = vec![
+            Instr::Section(".entry".to_string()),
+            Instr::Section(".text".to_string()),
+        ];
+
+        let linked = link(modules, script).expect("Linking should not fail");
+        let symbols: Vec<_> = linked
+            .symbols()
+            .into_iter()
+            .map(|symbol| (symbol.name, symbol.module, symbol.address, symbol.size))
+            .collect();
+        assert_eq!(
+            symbols,
+            &[
+                (s("start"), s("application.asm"), 0, None),
+                (s("memcpy"), s("library.asm"), 5, Some(1)),
+            ]
+        );
+
+        let map = linked.symbol_map();
+        let lines: Vec<_> = map.lines().skip(1).collect();
+        assert_eq!(
+            lines,
+            &[
+                "0x0000000000000000 -         Label     Local   start (application.asm)",
+                "0x0000000000000005 1         Function  Global  memcpy (library.asm)",
+            ]
+        );
+    }
+
+    fn s(source: &str) -> String {
+        source.to_string()
+    }
+
+    #[test]
+    fn test_nobits() {
+        let source = ".section .entry\n.u8 1\n.section .bss\n.skip 7\n.section .data\n.u8 2";
+
+        // A nobits section at the end only reserves space
+        let script = vec![
+            Instr::Section(".entry".to_string()),
+            Instr::Section(".data".to_string()),
+            Instr::Section(".bss".to_string()),
+        ];
+        let linked =
+            link(vec![module("test.asm", source)], script).expect("Linking should not fail");
+        assert_eq!(linked.linked, &[1, 2]);
+        assert_eq!(linked.size, 9);
+
+        // Anything after it has to come after the zeroes
+        let script = vec![
+            Instr::Section(".entry".to_string()),
+            Instr::Section(".bss".to_string()),
+            Instr::Section(".data".to_string()),
+        ];
+        let linked =
+            link(vec![module("test.asm", source)], script).expect("Linking should not fail");
+        assert_eq!(linked.linked, &[1, 0, 0, 0, 0, 0, 0, 0, 2]);
+        assert_eq!(linked.size, 9);
+        assert_eq!(linked.section_offset[0], &[0, 1, 8]);
+    }
+
+    #[test]
+    fn test_code_padding() {
+        let source = ".section .entry\n.u8 1, 2\n.section .text, \"rx\", 8\nhalt\n.section .data, \"rw\", 8\n.u8 3";
+        let script = vec![
+            Instr::Section(".entry".to_string()),
+            Instr::Section(".text".to_string()),
+            Instr::Section(".data".to_string()),
+        ];
+        let linked =
+            link(vec![module("test.asm", source)], script).expect("Linking should not fail");
+        assert_eq!(
+            linked.linked,
+            &[
+                1, 2, 0x20, 0x00, 0x20, 0x00, 0x20, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 3
+            ]
+        );
+
+        // Zeros would be a `halt` between the code sections
+        let source = ".section .entry\nhalt\n.section .text, \"rx\", 4\nhalt";
+        let script = vec![
+            Instr::Section(".entry".to_string()),
+            Instr::Section(".text".to_string()),
+        ];
+        assert!(link(vec![module("test.asm", source)], script).is_err());
+    }
+
+    #[test]
+    fn test_commons() {
+        let modules = vec![
+            module(
+                "a.asm",
+                ".comm buffer, 16, 4\n.comm flags, 1\n.section .entry\n.u32 buffer, flags",
+            ),
+            module("b.asm", ".comm buffer, 32, 8\n.comm shared, 4"),
+            module("c.asm", ".global shared\n.section .data\nshared: .u32 7"),
+        ];
+        let script = vec![
+            Instr::Section(".entry".to_string()),
+            Instr::Section(".data".to_string()),
+        ];
+
+        let linked = link(modules, script).expect("Linking should not fail");
+        assert_eq!(linked.linked, &[16, 0, 0, 0, 48, 0, 0, 0, 7, 0, 0, 0]);
+        assert_eq!(linked.size, 49);
+
+        let symbols: Vec<_> = linked
+            .symbols()
+            .into_iter()
+            .map(|symbol| (symbol.name, symbol.address, symbol.size))
+            .collect();
+        assert_eq!(
+            symbols,
+            &[
+                (s("shared"), 8, None),
+                (s("buffer"), 16, Some(32)),
+                (s("flags"), 48, Some(1)),
+            ]
+        );
+    }
 }
@@ -1,4 +1,5 @@
 mod assembler;
+mod diagnostic;
 mod expression;
 mod instruction;
 mod lexer;
@@ -13,6 +14,7 @@ use std::{
     collections::{HashMap, btree_map::Entry},
     fs::{File, OpenOptions},
     io::Write,
+    path::PathBuf,
     process::ExitCode,
     time::Instant,
 };
@@ -37,6 +39,23 @@ struct Args {
 
     #[clap(long, default_value_t = false)]
     map: bool,
+
+    /// Directory to search for .include and .incbin files, can be given more than once
+    #[arg(short = 'I', value_name = "DIR")]
+    include: Vec<PathBuf>,
+
+    /// Report symbols that are neither defined nor declared with .extern when assembling,
+    /// instead of leaving them for the linker
+    #[clap(long, default_value_t = false)]
+    strict: bool,
+
+    /// Fail if assembling any file produces a warning
+    #[clap(long, default_value_t = false)]
+    warnings_as_errors: bool,
+
+    /// Writes the address of every symbol in the linked program to FILE
+    #[arg(long, value_name = "FILE")]
+    symbol_map: Option<PathBuf>,
 }
 
 fn output_opcode_map() {
@@ -186,7 +205,12 @@ fn main() -> ExitCode {
             }
         };
 
-        let assembler = match Assembler::assemble(filename.clone(), text) {
+        let assembled = if args.include.is_empty() {
+            Assembler::assemble(filename.clone(), text)
+        } else {
+            Assembler::assemble_with_include_paths(filename.clone(), text, args.include.clone())
+        };
+        let mut assembler = match assembled {
             Ok(assembler) => assembler,
             Err(e) => {
                 println!("{e}");
@@ -194,6 +218,9 @@ fn main() -> ExitCode {
             }
         };
 
+        assembler.strict = args.strict;
+        assembler.warnings_as_errors = args.warnings_as_errors;
+
         let module = match Module::try_from(assembler) {
             Ok(module) => module,
             Err(e) => {
@@ -241,6 +268,17 @@ fn main() -> ExitCode {
     }
 
     println!("Wrote {} bytes", program.linked.len());
+    // Trailing nobits sections aren't written but still take up memory when the program is loaded
+    if program.size > program.linked.len() {
+        println!("Loaded size: {} bytes", program.size);
+    }
+
+    if let Some(path) = &args.symbol_map
+        && let Err(e) = std::fs::write(path, program.symbol_map())
+    {
+        println!("Error writing symbol map: {e}");
+        return ExitCode::FAILURE;
+    }
 
     ExitCode::SUCCESS
 }
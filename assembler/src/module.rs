@@ -1,12 +1,12 @@
 use std::collections::HashMap;
 
-use crate::assembler::symbol_table::{self, SymbolTable};
+use crate::assembler::symbol_table::{self, Binding, Symbol, SymbolTable};
 use crate::assembler::{self, Assembler};
 use crate::expression::{BinaryOp, Node};
 use crate::opcode::Relocation;
 use crate::section::{Section, SectionMap};
 
-use anyhow::{Error, Result, anyhow};
+use anyhow::{Context, Error, Result, anyhow};
 
 #[derive(Debug, Clone)]
 pub struct RelocationEntry {
@@ -22,10 +22,6 @@ pub struct RelocationEntry {
     pub offset: usize,
 }
 
-fn valid_operation_on_undefined_symbol(op: BinaryOp) -> bool {
-    matches!(op, BinaryOp::Add | BinaryOp::Sub)
-}
-
 ///
 /// Parses `expr` and returns a tuple in the format of (symbol, addend) where symbol is an
 /// either an undefined symbol, or a label in a section seperate from the one the expression comes from,
@@ -37,6 +33,7 @@ fn valid_operation_on_undefined_symbol(op: BinaryOp) -> bool {
 /// * `assembler` - The assembler the expression comes from
 /// * `section` - The section index the expression comes from
 /// * `expr` - The expression to evalute
+/// * `location` - The section and offset of `.` for an `.assert` that refers to it
 ///
 ///
 /// # Errors
@@ -47,13 +44,26 @@ fn evaluate_expression(
     assembler: &Assembler,
     section: usize,
     expr: &Box<Node>,
+    location: Option<(usize, u64)>,
 ) -> Result<(String, u64)> {
+    // `.` isn't in the symbol table, it's a label at `location` instead
+    let get_symbol = |name: &str| match location {
+        Some((section, offset)) if name == "." => Some(Symbol {
+            section_index: Some(section),
+            type_: symbol_table::Type::Label,
+            value: offset,
+            binding: Binding::Local,
+            size: None,
+        }),
+        _ => assembler.symbols.get_symbol(name),
+    };
+
     let result = match &**expr {
         // The values of registers are irrelevant, we only care about constant displacements
         Node::Register(_) => (String::new(), 0),
 
         Node::Constant(value) => (String::new(), *value),
-        Node::Identifier(symbol) => match assembler.symbols.get_symbol(&symbol) {
+        Node::Identifier(symbol) => match get_symbol(symbol) {
             Some(value) => {
                 // The symbol is label, otherwise it's a constant value
                 if let Some(_) = value.section_index {
@@ -65,11 +75,40 @@ fn evaluate_expression(
                     (String::new(), value.value)
                 }
             }
-            None => (symbol.clone(), 0),
+            // A `.equ` that couldn't be resolved to a symbol of this module is an alias of a symbol
+            // from another one
+            None => match assembler.equs.iter().find(|equ| equ.name == *symbol) {
+                Some(equ) => evaluate_expression(assembler, equ.section, &equ.expr, None)?,
+                None => (symbol.clone(), 0),
+            },
         },
         Node::BinaryOp { op, left, right } => {
-            let (left_symbol, mut left_addend) = evaluate_expression(assembler, section, left)?;
-            let (right_symbol, right_addend) = evaluate_expression(assembler, section, right)?;
+            let (left_symbol, left_addend) =
+                evaluate_expression(assembler, section, left, location)?;
+            let (right_symbol, right_addend) =
+                evaluate_expression(assembler, section, right, location)?;
+
+            // If both symbols are labels in the same section then their difference is a constant
+            if *op == BinaryOp::Sub
+                && !left_symbol.is_empty()
+                && !right_symbol.is_empty()
+                && let Some(left) = get_symbol(&left_symbol)
+                && let Some(right) = get_symbol(&right_symbol)
+                && let Some(left_section) = left.section_index
+                && let Some(right_section) = right.section_index
+            {
+                if left_section != right_section {
+                    return Err(anyhow!(
+                        "Cannot subtract labels {left_symbol} and {right_symbol} because they are in different sections"
+                    ));
+                }
+
+                let difference = left.value.wrapping_sub(right.value);
+                let addend = left_addend
+                    .wrapping_sub(right_addend)
+                    .wrapping_add(difference);
+                return Ok((String::new(), addend));
+            }
 
             let symbol = if left_symbol.is_empty() && right_symbol.is_empty() {
                 String::new()
@@ -80,35 +119,23 @@ fn evaluate_expression(
                     return Err(anyhow!("Cannot subtract a relocatable symbol"));
                 }
                 right_symbol
-            }
-            /* If both symbols are offsets into the same section that we can calculate their difference */
-            else if *op == BinaryOp::Sub
-                && !left_symbol.is_empty()
-                && !right_symbol.is_empty()
-                && let Some(left) = assembler.symbols.get_symbol(&left_symbol)
-                && let Some(right) = assembler.symbols.get_symbol(&right_symbol)
-                && let Some(left_section) = left.section_index
-                && let Some(right_section) = right.section_index
-                && left_section == right_section
-                && left_section == section
-            {
-                return Err(anyhow!("Failed to create relocation"));
-                // TODO: This is broken
-                // left_addend = left_addend.wrapping_add(left.value.wrapping_sub(right.value));
-                // String::new()
             } else {
                 return Err(anyhow!("Failed to create relocation"));
             };
 
-            if !symbol.is_empty() && *op != BinaryOp::Add && *op != BinaryOp::Sub {
+            if !symbol.is_empty() && !op.is_relocatable() {
                 return Err(anyhow!("Invalid operation on relocatable symbol"));
             }
 
+            if matches!(op, BinaryOp::Div | BinaryOp::Mod) && right_addend == 0 {
+                return Err(anyhow!("Division by zero"));
+            }
+
             let new_addend = op.calculate(left_addend, right_addend);
             (symbol, new_addend)
         }
         Node::UnaryOp { op, expr } => {
-            let (symbol, addend) = evaluate_expression(assembler, section, expr)?;
+            let (symbol, addend) = evaluate_expression(assembler, section, expr, location)?;
 
             if !symbol.is_empty() {
                 return Err(anyhow!(
@@ -120,16 +147,95 @@ fn evaluate_expression(
 
             (String::new(), new_addend)
         }
-        Node::Expression(expr) => evaluate_expression(assembler, section, expr)?,
+        Node::Expression(expr) => evaluate_expression(assembler, section, expr, location)?,
+        Node::Call { function, args } => (
+            String::new(),
+            assembler.evaluate_call(*function, args, section)?,
+        ),
     };
 
     Ok(result)
 }
 
+/// Writes `value` into `section` at `offset` the same way the linker would resolve `relocation`
+///
+/// A constant PC relative target is an offset into `section` like it is when the instruction is
+/// emitted, so the displacement from the end of the field to it is known without the linker
+fn patch_constant(
+    section: &mut Section,
+    relocation: Relocation,
+    offset: usize,
+    value: u64,
+) -> Result<()> {
+    let signed = value as i64;
+    let out_of_bounds = || anyhow!("Value ({signed}) out of bounds for {relocation:?}");
+    let displacement = |width: usize| value.wrapping_sub((offset + width) as u64) as i64;
+
+    match relocation {
+        Relocation::None => {}
+        Relocation::Abs8 => section.replace_bytes(offset, &(value as u8).to_le_bytes()),
+        Relocation::Abs16 => section.replace_bytes(offset, &(value as u16).to_le_bytes()),
+        Relocation::Abs32 => section.replace_bytes(offset, &(value as u32).to_le_bytes()),
+        Relocation::Abs64 => section.replace_bytes(offset, &value.to_le_bytes()),
+        Relocation::Abs8S => {
+            let value = i8::try_from(signed).map_err(|_| out_of_bounds())?;
+            section.replace_bytes(offset, &value.to_le_bytes());
+        }
+        Relocation::Abs16S => {
+            let value = i16::try_from(signed).map_err(|_| out_of_bounds())?;
+            section.replace_bytes(offset, &value.to_le_bytes());
+        }
+        Relocation::Abs32S => {
+            let value = i32::try_from(signed).map_err(|_| out_of_bounds())?;
+            section.replace_bytes(offset, &value.to_le_bytes());
+        }
+        Relocation::Abs64S => section.replace_bytes(offset, &signed.to_le_bytes()),
+        Relocation::PC8 => {
+            let value = i8::try_from(displacement(1))
+                .context("Displacement is too large to fit in 1 byte")?;
+            section.replace_bytes(offset, &value.to_le_bytes());
+        }
+        Relocation::PC32 => {
+            let value = i32::try_from(displacement(4))
+                .context("Displacement is too large to fit in 4 bytes")?;
+            section.replace_bytes(offset, &value.to_le_bytes());
+        }
+        Relocation::PC64 => section.replace_bytes(offset, &displacement(8).to_le_bytes()),
+    }
+
+    Ok(())
+}
+
+/// A symbol declared with `.comm`. The linker merges every common symbol with the same name and
+/// allocates it after the last section
+#[derive(Debug, Clone)]
+pub struct Common {
+    pub name: String,
+    pub size: u64,
+    pub alignment: u64,
+}
+
+/// An `.assert` that depends on the address of a label, which is checked by the linker
+#[derive(Debug)]
+pub struct LinkAssertion {
+    pub expr: Box<Node>,
+    /// The section and offset of `.` if the expression refers to it
+    pub location: Option<(usize, u64)>,
+    /// The formatted error shown if the assertion fails, since the source code isn't around
+    /// anymore when linking
+    pub diagnostic: String,
+}
+
 pub struct Module {
     pub filename: String,
     pub symbols: SymbolTable,
     pub global_symbols: Vec<String>,
+    /// Symbols declared with `.weak` that the module doesn't define. If no other module defines
+    /// them either they resolve to 0
+    pub weak_references: Vec<String>,
+    pub assertions: Vec<LinkAssertion>,
+    /// Symbols declared with `.comm`, each name only once
+    pub commons: Vec<Common>,
 
     pub relocations: Vec<RelocationEntry>,
     pub sections: SectionMap,
@@ -137,12 +243,112 @@ pub struct Module {
 
 impl TryFrom<Assembler> for Module {
     type Error = anyhow::Error;
-    fn try_from(value: Assembler) -> Result<Self, Error> {
+    fn try_from(mut value: Assembler) -> Result<Self, Error> {
         let mut relocations = Vec::new();
+        // Expressions that turned out to be constants once every label was known
+        let mut constants = Vec::new();
+
+        if value.warnings_as_errors && value.warning_count > 0 {
+            return Err(anyhow!(
+                "in {}:\n\tWarnings are treated as errors",
+                value.filename
+            ));
+        }
+
+        // Now that every label is known, `.equ`s that referred to later symbols become constants
+        // or labels. Aliases of symbols from other modules are left to be resolved through
+        // `value.equs`
+        let mut index = 0;
+        while index < value.equs.len() {
+            let equ = &value.equs[index];
+            let (symbol, addend) = evaluate_expression(&value, equ.section, &equ.expr, None)
+                .map_err(|e| anyhow!("{}", value.format_error(&e, equ.span, None)))?;
+
+            let (symbol_value, type_, section) = if symbol.is_empty() {
+                (addend, symbol_table::Type::Constant, None)
+            } else {
+                match value.symbols.get_symbol(&symbol) {
+                    Some(label) if label.section_index.is_some() => (
+                        label.value.wrapping_add(addend),
+                        symbol_table::Type::Label,
+                        label.section_index,
+                    ),
+                    _ => {
+                        index += 1;
+                        continue;
+                    }
+                }
+            };
+
+            let equ = value.equs.remove(index);
+            if let Err(e) = value
+                .symbols
+                .insert_symbol(equ.name, symbol_value, type_, section)
+            {
+                return Err(anyhow!("{}", value.format_error(&e, equ.span, None)));
+            }
+        }
+
+        // Assertions that only depend on the distance between labels can be checked now
+        let mut assertions = Vec::new();
+        for assertion in value.assertions.iter() {
+            let e = anyhow!("{}", assertion.message);
+            let location = assertion.location;
+            match evaluate_expression(&value, assertion.section, &assertion.expr, location) {
+                Ok((symbol, 0)) if symbol.is_empty() => {
+                    return Err(anyhow!("{}", value.format_error(&e, assertion.span, None)));
+                }
+                Ok((symbol, _)) if symbol.is_empty() => {}
+                _ => {
+                    // The linker doesn't know about aliases of symbols from other modules
+                    let mut expr = assertion.expr.clone();
+                    let alias = |id: &str| value.equs.iter().find(|equ| equ.name == id);
+                    loop {
+                        let mut aliases = false;
+                        expr.for_each_identifier(&mut |id| aliases |= alias(id).is_some());
+                        if !aliases {
+                            break;
+                        }
+                        expr.substitute(&|id| alias(id).map(|equ| (*equ.expr).clone()));
+                    }
+
+                    assertions.push(LinkAssertion {
+                        expr,
+                        location,
+                        diagnostic: value.format_error(&e, assertion.span, None),
+                    });
+                }
+            }
+        }
+
+        // A module can declare the same common symbol more than once, which is merged like the
+        // linker merges them across modules
+        let mut commons: Vec<Common> = Vec::new();
+        for (common, span) in value.commons.iter() {
+            if value.symbols.get_symbol(&common.name).is_some() {
+                let e = anyhow!(
+                    "{} is a common symbol, so it can't be defined in this module",
+                    common.name
+                );
+                return Err(anyhow!("{}", value.format_error(&e, *span, None)));
+            }
+
+            match commons.iter_mut().find(|merged| merged.name == common.name) {
+                Some(merged) => {
+                    merged.size = merged.size.max(common.size);
+                    merged.alignment = merged.alignment.max(common.alignment);
+                }
+                None => commons.push(common.clone()),
+            }
+        }
+        // Common symbols are always global
+        value
+            .global_symbols
+            .retain(|symbol| !commons.iter().any(|common| &common.name == symbol));
 
         // All global symbols must be actual symbols within the module
         for symbol in value.global_symbols.iter() {
-            if value.symbols.get_symbol(symbol).is_none() {
+            if !value.symbols.set_binding(symbol, Binding::Global) {
                 return Err(anyhow!(
                     "in {}:\n\tGlobal symbol {} has no definition",
                     value.filename,
@@ -151,22 +357,76 @@ impl TryFrom<Assembler> for Module {
             }
         }
 
+        // Weak symbols are exported like global ones when they're defined, and are weak
+        // references otherwise
+        let mut weak_references = Vec::new();
+        for symbol in value.weak_symbols.iter() {
+            if !value.symbols.set_binding(symbol, Binding::Weak) {
+                weak_references.push(symbol.clone());
+            } else if !value.global_symbols.contains(symbol) {
+                value.global_symbols.push(symbol.clone());
+            }
+        }
+
+        for (symbol, type_, span) in value.symbol_types.iter() {
+            let e = match value.symbols.get_symbol_mut(symbol) {
+                Some(symbol) if symbol.section_index.is_some() => {
+                    symbol.type_ = *type_;
+                    continue;
+                }
+                Some(_) => anyhow!("Only labels can have a type, but {symbol} is a constant"),
+                None => anyhow!("Symbol {symbol} has no definition"),
+            };
+            return Err(anyhow!("{}", value.format_error(&e, *span, None)));
+        }
+
+        for (symbol, size, span) in value.symbol_sizes.iter() {
+            let e = match value.symbols.get_symbol_mut(symbol) {
+                Some(symbol) if symbol.section_index.is_some() => {
+                    symbol.size = Some(*size);
+                    continue;
+                }
+                Some(_) => anyhow!("Only labels can have a size, but {symbol} is a constant"),
+                None => anyhow!("Symbol {symbol} has no definition"),
+            };
+            return Err(anyhow!("{}", value.format_error(&e, *span, None)));
+        }
+
+        // In strict mode every symbol that isn't defined has to be declared with `.extern`
+        let mut undefined = Vec::new();
+
         for forward_reference in value.forward_references.iter() {
             let (symbol, addend) = match evaluate_expression(
                 &value,
                 forward_reference.section,
                 &forward_reference.expr,
+                None,
             ) {
                 Ok(result) => result,
                 Err(e) => {
                     return Err(anyhow!(
-                        "{}:{}\n\t{e}",
-                        value.filename,
-                        forward_reference.line_number
+                        "{}",
+                        value.format_error(&e, forward_reference.span, None)
                     ));
                 }
             };
 
+            if value.strict
+                && !symbol.is_empty()
+                && value.symbols.get_symbol(&symbol).is_none()
+                && !value.extern_symbols.contains(&symbol)
+                && !weak_references.contains(&symbol)
+                && !commons.iter().any(|common| common.name == symbol)
+            {
+                let e = anyhow!("Symbol {symbol} is not defined or declared with .extern");
+                undefined.push(value.format_error(&e, forward_reference.span, None));
+            }
+
+            if symbol.is_empty() {
+                constants.push((forward_reference, addend));
+                continue;
+            }
+
             let relocation = RelocationEntry {
                 relocation: forward_reference.relocation,
                 symbol,
@@ -178,13 +438,161 @@ impl TryFrom<Assembler> for Module {
             relocations.push(relocation);
         }
 
+        if !undefined.is_empty() {
+            return Err(anyhow!("{}", undefined.join("\n")));
+        }
+
+        for (forward_reference, addend) in constants {
+            let section = &mut value.sections[forward_reference.section];
+            if let Err(e) = patch_constant(
+                section,
+                forward_reference.relocation,
+                forward_reference.offset,
+                addend,
+            ) {
+                return Err(anyhow!(
+                    "{}",
+                    value.format_error(&e, forward_reference.span, None)
+                ));
+            }
+        }
+
         Ok(Self {
             filename: value.filename,
             symbols: value.symbols,
             global_symbols: value.global_symbols,
+            weak_references,
+            assertions,
+            commons,
 
             relocations,
             sections: value.sections,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(source: &str) -> Result<Module> {
+        let assembler = Assembler::assemble(String::from("test.asm"), source.to_string())?;
+        Module::try_from(assembler)
+    }
+
+    #[test]
+    fn test_label_difference() {
+        let module = build(
+            ".section .entry\nstart:\n.u32 end - start, end - start + 2, 0\n.u16 end - mid\nmid:\n.u8 1, 2\nend:",
+        )
+        .expect("Module should build");
+
+        assert!(module.relocations.is_empty());
+        assert_eq!(
+            module.sections[0].data.get_ref(),
+            &[16, 0, 0, 0, 18, 0, 0, 0, 0, 0, 0, 0, 2, 0, 1, 2]
+        );
+
+        let module = build(".section .entry\nstart:\nmov r0, end - start\nend:")
+            .expect("Module should build");
+        assert!(module.relocations.is_empty());
+        assert_eq!(module.sections[0].data.get_ref()[2], 10);
+
+        // Labels from other sections are still left for the linker
+        let module = build(".section .entry\n.u64 start\n.section .data\nstart:")
+            .expect("Module should build");
+        assert_eq!(module.relocations.len(), 1);
+        assert_eq!(module.relocations[0].symbol, "start");
+
+        assert!(build(".section .entry\n.u32 end - start\nstart:\n.section .data\nend:").is_err());
+    }
+
+    fn build_strict(source: &str) -> Result<Module> {
+        let mut assembler = Assembler::assemble(String::from("test.asm"), source.to_string())?;
+        assembler.strict = true;
+        Module::try_from(assembler)
+    }
+
+    #[test]
+    fn test_strict() {
+        let source = ".section .entry\njmp memcpy\n.u64 mmeset + 8";
+        let module = build(source).expect("Undefined symbols are left for the linker");
+        assert_eq!(module.relocations.len(), 2);
+
+        let error = build_strict(source).err().unwrap().to_string();
+        assert!(error.contains("Symbol memcpy is not defined or declared with .extern"));
+        assert!(error.contains(" --> test.asm:2:5"));
+        assert!(error.contains("Symbol mmeset is not defined or declared with .extern"));
+        assert!(error.contains(" --> test.asm:3:6"));
+
+        let source = ".extern memcpy, memset\n.section .entry\njmp memcpy\n.u64 memset + 8\n.u64 data\n.section .data\ndata:";
+        assert_eq!(build_strict(source).unwrap().relocations.len(), 3);
+
+        // Weak references don't need a definition anywhere
+        let module = build_strict(".weak hook\n.section .entry\n.u64 hook").unwrap();
+        assert_eq!(module.weak_references, &["hook"]);
+    }
+
+    #[test]
+    fn test_set() {
+        let source = ".section .entry\nstart:\n.set n, 1\n.u8 n, end - start + n\n.set n, n + 1\n.u8 n\nend:";
+        let module = build(source).expect("Module should build");
+        assert_eq!(module.sections[0].data.get_ref(), &[1, 4, 2]);
+    }
+
+    #[test]
+    fn test_pc_relative_constant() {
+        // `distance` is a constant once the module is built, so the jump is resolved like a jump
+        // to that offset in .entry would be when it's emitted
+        let source = ".section .entry\nstart: jmp distance\nhalt\nend:\n.equ distance, end - start";
+        let module = build(source).expect("Module should build");
+        assert!(module.relocations.is_empty());
+        assert_eq!(module.sections[0].data.get_ref(), &[0x10, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_forward_divisor() {
+        let source = ".section .entry\n.u8 10 % n, 10 / n\n.equ n, 3";
+        let module = build(source).expect("Module should build");
+        assert_eq!(module.sections[0].data.get_ref(), &[1, 3]);
+
+        let error = build(".section .entry\n.u8 10 % n\n.equ n, 0")
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("Division by zero"));
+    }
+
+    #[test]
+    fn test_forward_equ() {
+        let source = "
+        .equ size, end - start
+        .equ twice, size * 2
+        .equ entry, start + 1
+        .equ print, puts + 4
+        .section .entry
+        start:
+        .u8 size, twice
+        .u32 0
+        end:
+        jmp print
+        ";
+        let module = build(source).expect("Module should build");
+        assert_eq!(module.sections[0].data.get_ref()[..6], [6, 12, 0, 0, 0, 0]);
+
+        let entry = module.symbols.get_symbol("entry").unwrap();
+        assert_eq!((entry.section_index, entry.value), (Some(0), 1));
+
+        // Aliases of symbols from other modules become relocations against that symbol
+        assert_eq!(module.relocations.len(), 1);
+        assert_eq!(module.relocations[0].symbol, "puts");
+        assert_eq!(module.relocations[0].addend, 4);
+
+        let error = build(".equ a, later\n.section .entry\nlater:\na:")
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("Symbol already defined"));
+        assert!(error.contains(" --> test.asm:1:9"));
+    }
+}
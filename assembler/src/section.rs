@@ -1,37 +1,94 @@
 use std::{
     collections::{HashMap, hash_map::Entry},
     io::{Cursor, Write},
-    num::NonZero,
-    ops,
     rc::Rc,
 };
 
-use anyhow::{Context, Result, anyhow, bail};
+use anyhow::{Context, Result, bail};
+use bitflags::bitflags;
 
 use crate::{
-    instruction::{Instruction, Operand},
-    section,
+    instruction::Mnemonic,
+    opcode::{EncodingFlags, OperandFlags, get_encodings},
 };
 
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct SectionFlags: u8 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXEC = 1 << 2;
+        /// The section only reserves zeroed memory and takes no space in the output, like `.bss`
+        const NOBITS = 1 << 3;
+    }
+}
+
+impl SectionFlags {
+    /// The flags of a section declared without any, based on its name
+    pub fn implied(name: &str) -> Self {
+        let is = |prefix: &str| name == prefix || name.starts_with(&format!("{prefix}."));
+
+        if is(".bss") {
+            Self::READ | Self::WRITE | Self::NOBITS
+        } else if is(".text") || is(".entry") {
+            Self::READ | Self::EXEC
+        } else if is(".rodata") {
+            Self::READ
+        } else {
+            Self::READ | Self::WRITE
+        }
+    }
+
+    /// Parses the flags argument of `.section`, like `"rw"` or `"rw nobits"`. `r`, `w` and `x`
+    /// are read, write and execute, and `nobits` is a section like `.bss`
+    pub fn parse_flags(flags: &str) -> Result<Self> {
+        let mut result = Self::empty();
+        for word in flags.split_whitespace() {
+            if word == "nobits" {
+                result |= Self::NOBITS;
+                continue;
+            }
+
+            for flag in word.chars() {
+                result |= match flag {
+                    'r' => Self::READ,
+                    'w' => Self::WRITE,
+                    'x' => Self::EXEC,
+                    _ => bail!("Unknown section flag '{flag}'. Expected r, w, x or nobits"),
+                };
+            }
+        }
+
+        Ok(result)
+    }
+}
+
 #[derive(Debug)]
 pub struct Section {
     /// The name of the section
     pub name: Rc<str>,
     /// The alignment this section requires
     pub alignment: u64,
+    pub flags: SectionFlags,
+    /// Always empty for nobits sections, where only the cursor moves
     pub data: Cursor<Vec<u8>>,
     // pub section_data: Vec<SectionEntry>,
 }
 
 impl Section {
-    fn new(name: Rc<str>) -> Self {
+    fn new(name: Rc<str>, flags: SectionFlags) -> Self {
         Self {
             name,
             alignment: 1,
+            flags,
             data: Cursor::new(Vec::new()),
         }
     }
 
+    pub fn is_nobits(&self) -> bool {
+        self.flags.contains(SectionFlags::NOBITS)
+    }
+
     pub fn replace_bytes(&mut self, offset: usize, bytes: &[u8]) {
         let old_position = self.data.position();
         self.data
@@ -41,15 +98,58 @@ impl Section {
         self.data.set_position(old_position);
     }
 
-    pub fn align(&mut self, align: u64) {
-        if align > self.alignment {
-            self.alignment = align;
+    /// Gets the number of bytes from the cursor to the next multiple of `align`
+    ///
+    /// # Errors
+    /// Returns Err if `align` isn't a nonzero power of two
+    pub fn padding(&self, align: u64) -> Result<u64> {
+        if !align.is_power_of_two() {
+            bail!("Alignment must be a nonzero power of two, but it is {align}");
         }
 
         let cursor: u64 = self.cursor().try_into().expect("Value too large");
+        Ok((align - (cursor % align)) % align)
+    }
 
-        let n = (align - (cursor % align)) % align;
-        self.data.set_position(cursor + n);
+    /// Adds `count` bytes of `fill`. Without a fill byte, executable sections are padded with
+    /// no-ops so that execution can fall through the padding, and other sections with zeros
+    ///
+    /// # Errors
+    /// Returns Err if the padding of an executable section is too short to be made of no-ops,
+    /// since zeros would be a `halt` in the middle of the code
+    pub fn pad(&mut self, count: u64, fill: Option<u8>) -> Result<()> {
+        match fill {
+            Some(fill) if fill != 0 => {
+                self.write_bytes(&vec![fill; count.try_into().expect("Value too large")]);
+            }
+            None if self.flags.contains(SectionFlags::EXEC) && !self.is_nobits() => {
+                let nops =
+                    nop_padding(count.try_into().expect("Value too large")).with_context(|| {
+                        format!(
+                            "{count} bytes of padding can't be made of no-ops, use a fill byte \
+                             to pad code with something else"
+                        )
+                    })?;
+                self.write_bytes(&nops);
+            }
+            _ => self.reserve(count),
+        }
+
+        Ok(())
+    }
+
+    /// Pads the section to a multiple of `align` like `pad`, and makes the section at least as
+    /// aligned as `align`
+    ///
+    /// # Errors
+    /// Returns Err if `align` isn't a nonzero power of two or the padding can't be made of
+    /// no-ops
+    pub fn align(&mut self, align: u64) -> Result<()> {
+        let count = self.padding(align)?;
+        self.pad(count, None)?;
+        self.alignment = self.alignment.max(align);
+
+        Ok(())
     }
 
     pub fn write_u8(&mut self, byte: u8) {
@@ -72,9 +172,25 @@ impl Section {
         _ = self.data.write(bytes);
     }
 
+    /// Adds `count` zeroed bytes. Nobits sections only move the cursor
+    pub fn reserve(&mut self, count: u64) {
+        if self.is_nobits() {
+            self.data.set_position(self.data.position() + count);
+        } else {
+            let size = self.data.get_ref().len() as u64;
+            let end = self.data.position() + count;
+            self.data.get_mut().resize(size.max(end) as usize, 0);
+            self.data.set_position(end);
+        }
+    }
+
     /// Get's the current size of the section in bytes
     pub fn size(&self) -> usize {
-        self.data.get_ref().len()
+        if self.is_nobits() {
+            self.cursor()
+        } else {
+            self.data.get_ref().len()
+        }
     }
 
     /// Gets the current byte position of the
@@ -83,11 +199,46 @@ impl Section {
     }
 }
 
+/// Builds `count` bytes of instructions that do nothing. They are `mov r0, r0` from the `Mov`
+/// table, with a jump to the next instruction first to make up an odd count, so 1 and 3 bytes
+/// can't be made of no-ops
+pub fn nop_padding(count: usize) -> Option<Vec<u8>> {
+    if count == 1 || count == 3 {
+        return None;
+    }
+
+    let mov = get_encodings(Mnemonic::Mov)
+        .iter()
+        .find(|encoding| encoding.operands[1].contains(OperandFlags::GP_REG))
+        .expect("Mov should have a register to register encoding");
+    let jmp = get_encodings(Mnemonic::Jmp)
+        .iter()
+        .find(|encoding| encoding.options.contains(EncodingFlags::JMP))
+        .expect("Jmp should have a displacement encoding");
+
+    let mut padding = Vec::with_capacity(count);
+    if count % 2 == 1 {
+        // The displacement is relative to the end of the jump, so 0 is the next instruction
+        padding.push(jmp.opcode);
+        padding.extend(0u32.to_le_bytes());
+    }
+    while padding.len() < count {
+        // Both registers are r0
+        padding.extend([mov.opcode, 0x00]);
+    }
+
+    Some(padding)
+}
+
 #[derive(Debug)]
 pub struct SectionMap {
     sections: Vec<Section>,
     section_map: HashMap<Rc<str>, usize>,
     current_section: Option<usize>,
+    /// The section that was current before the last switch, which `.previous` returns to
+    previous_section: Option<usize>,
+    /// The current and previous sections saved by `push_section`, innermost last
+    stack: Vec<(Option<usize>, Option<usize>)>,
 }
 
 impl std::ops::Index<usize> for SectionMap {
@@ -128,21 +279,94 @@ impl SectionMap {
             sections: Vec::new(),
             section_map: HashMap::new(),
             current_section: None,
+            previous_section: None,
+            stack: Vec::new(),
         }
     }
 
-    pub fn set_section(&mut self, name: impl Into<Rc<str>>) {
+    fn switch_to(&mut self, index: usize) {
+        self.previous_section = self.current_section;
+        self.current_section = Some(index);
+    }
+
+    /// Switches to the section `name`, creating it if it doesn't exist yet. A new section gets
+    /// `flags`, or the flags implied by its name if there are none
+    ///
+    /// # Errors
+    /// Returns Err if the section already exists with flags other than `flags`
+    pub fn set_section(
+        &mut self,
+        name: impl Into<Rc<str>>,
+        flags: Option<SectionFlags>,
+    ) -> Result<()> {
         let name: Rc<str> = name.into();
         match self.section_map.entry(name.clone()) {
-            Entry::Occupied(entry) => self.current_section = Some(*entry.get()),
+            Entry::Occupied(entry) => {
+                let section = &self.sections[*entry.get()];
+                if let Some(flags) = flags
+                    && flags != section.flags
+                {
+                    bail!(
+                        "Section {name} was already declared with the flags {:?}",
+                        section.flags
+                    );
+                }
+                let index = *entry.get();
+                self.switch_to(index);
+            }
             Entry::Vacant(entry) => {
-                let section = Section::new(name);
+                let flags = flags.unwrap_or_else(|| SectionFlags::implied(&name));
+                let section = Section::new(name, flags);
                 let index = self.sections.len();
-                self.current_section = Some(index);
                 self.sections.push(section);
                 entry.insert(index);
+                self.switch_to(index);
             }
         }
+
+        Ok(())
+    }
+
+    /// Saves the current section and then switches to `name` like `set_section`, so that
+    /// `pop_section` can return to it
+    ///
+    /// # Errors
+    /// Returns Err if the section already exists with flags other than `flags`
+    pub fn push_section(
+        &mut self,
+        name: impl Into<Rc<str>>,
+        flags: Option<SectionFlags>,
+    ) -> Result<()> {
+        let saved = (self.current_section, self.previous_section);
+        self.set_section(name, flags)?;
+        self.stack.push(saved);
+        Ok(())
+    }
+
+    /// Returns to the section that was current before the last `push_section`
+    ///
+    /// # Errors
+    /// Returns Err if there was no `push_section` left to undo
+    pub fn pop_section(&mut self) -> Result<()> {
+        let (current, previous) = self
+            .stack
+            .pop()
+            .context(".popsection without a matching .pushsection")?;
+        self.current_section = current;
+        self.previous_section = previous;
+        Ok(())
+    }
+
+    /// Swaps the current section with the one that was current before the last switch
+    ///
+    /// # Errors
+    /// Returns Err if there was no section before the current one
+    pub fn previous(&mut self) -> Result<()> {
+        let previous = self
+            .previous_section
+            .context(".previous without an earlier section to return to")?;
+        self.switch_to(previous);
+        Ok(())
     }
 
     /// Returns a tuple containing the section id and a mutable reference to the section last set with `set_section`
@@ -156,6 +380,22 @@ impl SectionMap {
         }
     }
 
+    /// Like `get_section_mut`, but for writing initialized data, which nobits sections can't hold
+    ///
+    /// # Errors
+    /// Returns Err if there is no current section or it is a nobits section
+    pub fn get_data_section_mut(&mut self) -> Result<(usize, &mut Section)> {
+        let (current, section) = self.get_section_mut()?;
+        if section.is_nobits() {
+            bail!(
+                "Cannot put initialized data in {}, which is a nobits section",
+                section.name
+            );
+        }
+
+        Ok((current, section))
+    }
+
     /// Returns a tuple containing the section id and an immutable reference to the section last set with `set_section`
     ///
     /// # Errors
@@ -242,10 +482,10 @@ mod tests {
 
     #[test]
     fn test_align() {
-        let mut section = Section::new(Rc::from("Test section"));
+        let mut section = Section::new(Rc::from("Test section"), SectionFlags::READ);
 
         section.write_u8(1);
-        section.align(8);
+        section.align(8).unwrap();
 
         section.write_u64(0xabababababababab);
 
@@ -256,10 +496,10 @@ mod tests {
             ]
         );
 
-        let mut section = Section::new(Rc::from("Test section"));
+        let mut section = Section::new(Rc::from("Test section"), SectionFlags::READ);
 
         section.write_u8(1);
-        section.align(16);
+        section.align(16).unwrap();
 
         section.write_u64(0xabababababababab);
 
@@ -271,4 +511,52 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_section_stack() {
+        let mut sections = SectionMap::new();
+        let current = |sections: &SectionMap| {
+            let (_, section) = sections.get_section().unwrap();
+            section.name.to_string()
+        };
+
+        sections.set_section(".text", None).unwrap();
+        sections.push_section(".rodata", None).unwrap();
+        sections.set_section(".data", None).unwrap();
+        assert_eq!(current(&sections), ".data");
+        sections.previous().unwrap();
+        assert_eq!(current(&sections), ".rodata");
+        sections.previous().unwrap();
+        assert_eq!(current(&sections), ".data");
+
+        sections.pop_section().unwrap();
+        assert_eq!(current(&sections), ".text");
+        assert!(sections.pop_section().is_err());
+        assert!(SectionMap::new().previous().is_err());
+    }
+
+    #[test]
+    fn test_nop_padding() {
+        assert_eq!(nop_padding(4).unwrap(), &[0x20, 0x00, 0x20, 0x00]);
+        assert_eq!(
+            nop_padding(7).unwrap(),
+            &[0x10, 0x00, 0x00, 0x00, 0x00, 0x20, 0x00]
+        );
+        assert!(nop_padding(0).unwrap().is_empty());
+        assert!(nop_padding(1).is_none());
+        assert!(nop_padding(3).is_none());
+
+        let mut section = Section::new(Rc::from(".text"), SectionFlags::implied(".text"));
+        section.write_u16(1);
+        section.align(4).unwrap();
+        assert_eq!(section.data.get_ref(), &[1, 0, 0x20, 0x00]);
+        section.write_u8(1);
+        assert!(section.align(8).is_err());
+        assert_eq!(section.alignment, 4);
+        section.pad(3, Some(0xff)).unwrap();
+        section.align(8).unwrap();
+        assert_eq!(section.size(), 8);
+        assert!(section.align(0).is_err());
+        assert!(section.align(12).is_err());
+    }
 }
@@ -6,3 +6,10 @@ pub enum Size {
     U32 = 2,
     U64 = 3,
 }
+
+impl Size {
+    /// The number of bytes a value of this size takes up
+    pub fn bytes(&self) -> u64 {
+        1 << *self as u64
+    }
+}
@@ -1,4 +1,9 @@
-use crate::{instruction::Mnemonic, opcode::OperandFlags, tokens};
+use crate::{
+    diagnostic::{Span, SpanContext, error_at},
+    instruction::Mnemonic,
+    opcode::OperandFlags,
+    tokens,
+};
 use anyhow::{Context, Result, anyhow, bail};
 use clap::error::ContextKind;
 use core::fmt;
@@ -9,7 +14,7 @@ use std::{
 };
 use strum::{AsRefStr, EnumDiscriminants, IntoStaticStr};
 
-use super::lexer::Lexer;
+use super::lexer::{Unescaped, unescape};
 
 /// There are 16 general purpose registers.
 /// Garunteed for the register index to be between 0..=15
@@ -159,29 +164,152 @@ impl Display for Register {
     }
 }
 
-#[derive(Debug, Clone, Copy, IntoStaticStr, AsRefStr)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntoStaticStr, AsRefStr)]
 pub enum Directive {
     Section,
+    Pushsection,
+    Popsection,
+    Previous,
     Equ,
+    Set,
     Align,
     Skip,
     Global,
+    Extern,
+    Weak,
+    Comm,
+    Lcomm,
+    Type,
+    Size,
+    Func,
+    Endfunc,
+    Assert,
+    Error,
+    Warning,
+    Print,
     U8,
     U16,
     U32,
     U64,
+    F32,
+    F64,
     Ascii,
+    Macro,
+    Endm,
+    If,
+    Ifdef,
+    Ifndef,
+    Elif,
+    Else,
+    Endif,
+    Include,
+    Incbin,
+    Rept,
+    Irp,
+    Irpc,
+    Endr,
+    Struct,
+    Endstruct,
+    Instance,
+}
+
+impl Directive {
+    /// The directive as it is written in the source code
+    pub fn name(&self) -> &'static str {
+        match self {
+            Directive::Section => ".section",
+            Directive::Pushsection => ".pushsection",
+            Directive::Popsection => ".popsection",
+            Directive::Previous => ".previous",
+            Directive::Equ => ".equ",
+            Directive::Set => ".set",
+            Directive::Align => ".align",
+            Directive::Skip => ".skip",
+            Directive::Global => ".global",
+            Directive::Extern => ".extern",
+            Directive::Weak => ".weak",
+            Directive::Comm => ".comm",
+            Directive::Lcomm => ".lcomm",
+            Directive::Type => ".type",
+            Directive::Size => ".size",
+            Directive::Func => ".func",
+            Directive::Endfunc => ".endfunc",
+            Directive::Assert => ".assert",
+            Directive::Error => ".error",
+            Directive::Warning => ".warning",
+            Directive::Print => ".print",
+            Directive::U8 => ".u8",
+            Directive::U16 => ".u16",
+            Directive::U32 => ".u32",
+            Directive::U64 => ".u64",
+            Directive::F32 => ".f32",
+            Directive::F64 => ".f64",
+            Directive::Ascii => ".ascii",
+            Directive::Macro => ".macro",
+            Directive::Endm => ".endm",
+            Directive::If => ".if",
+            Directive::Ifdef => ".ifdef",
+            Directive::Ifndef => ".ifndef",
+            Directive::Elif => ".elif",
+            Directive::Else => ".else",
+            Directive::Endif => ".endif",
+            Directive::Include => ".include",
+            Directive::Incbin => ".incbin",
+            Directive::Rept => ".rept",
+            Directive::Irp => ".irp",
+            Directive::Irpc => ".irpc",
+            Directive::Endr => ".endr",
+            Directive::Struct => ".struct",
+            Directive::Endstruct => ".endstruct",
+            Directive::Instance => ".instance",
+        }
+    }
+
+    /// Whether the directive is part of an `.if` block
+    pub fn is_conditional(&self) -> bool {
+        matches!(
+            self,
+            Directive::If
+                | Directive::Ifdef
+                | Directive::Ifndef
+                | Directive::Elif
+                | Directive::Else
+                | Directive::Endif
+        )
+    }
+
+    /// Whether the directive starts a block that is closed by `.endr`
+    pub fn is_repetition(&self) -> bool {
+        matches!(self, Directive::Rept | Directive::Irp | Directive::Irpc)
+    }
+}
+
+/// Which definition of a numeric local label a reference points to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// `1b`, the closest definition before the reference
+    Backward,
+    /// `1f`, the closest definition after the reference
+    Forward,
 }
 
 #[derive(Debug, Clone, EnumDiscriminants)]
 #[strum_discriminants(name(TokenKind))]
 pub enum Token {
     Mnemonic(Mnemonic),
-    Ascii(Rc<str>),
+    /// A string literal with its escape sequences already decoded
+    Ascii(Rc<[u8]>),
+    /// A single quoted character like `'a'`, which is its value in an expression and a one
+    /// character string where a string is expected
+    Character(u64, Rc<[u8]>),
     Register(Register),
     Identifier(String),
     Directive(Directive),
     Number(u64),
+    /// A float literal, which can only be used by `.f32` and `.f64`
+    Float(f64),
+    /// A reference to a numeric local label like `1b` or `2f`
+    LocalLabel(u64, Direction),
     Equal,
     Comma,
     LBrace,
@@ -194,6 +322,20 @@ pub enum Token {
     Div,
     Caret,
     Ampersand,
+    Pipe,
+    Percent,
+    Tilde,
+    Exclamation,
+    ShiftLeft,
+    ShiftRight,
+    DoubleAmpersand,
+    DoublePipe,
+    DoubleEqual,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
     /// The @ symbol
     AtSign,
     Colon,
@@ -206,10 +348,14 @@ impl fmt::Display for Token {
         let value = match self {
             Self::Mnemonic(instr) => instr.as_ref(),
             Self::Ascii(_) => "string",
+            Self::Character(value, _) => &value.to_string(),
             Self::Register(register) => register.as_ref(),
             Self::Identifier(id) => id,
             Self::Directive(dir) => dir.as_ref(),
             Self::Number(num) => &num.to_string(),
+            Self::Float(num) => &num.to_string(),
+            Self::LocalLabel(label, Direction::Backward) => &format!("{label}b"),
+            Self::LocalLabel(label, Direction::Forward) => &format!("{label}f"),
             Self::Equal => "=",
             Self::Comma => ",",
             Self::LBrace => "(",
@@ -222,6 +368,20 @@ impl fmt::Display for Token {
             Self::Div => "/",
             Self::Caret => "^",
             Self::Ampersand => "&",
+            Self::Pipe => "|",
+            Self::Percent => "%",
+            Self::Tilde => "~",
+            Self::Exclamation => "!",
+            Self::ShiftLeft => "<<",
+            Self::ShiftRight => ">>",
+            Self::DoubleAmpersand => "&&",
+            Self::DoublePipe => "||",
+            Self::DoubleEqual => "==",
+            Self::NotEqual => "!=",
+            Self::Less => "<",
+            Self::LessEqual => "<=",
+            Self::Greater => ">",
+            Self::GreaterEqual => ">=",
             Self::AtSign => "@",
             Self::Colon => ":",
             Self::Dollar => "$",
@@ -249,34 +409,30 @@ impl Token {
 }
 
 #[derive(Debug)]
-pub struct TokenIter<'a, T: Iterator<Item = &'a str>> {
+pub struct TokenIter<'a, T: Iterator<Item = (&'a str, Span)>> {
     lexer: T,
 }
 
-impl<'a, T: Iterator<Item = &'a str>> Iterator for TokenIter<'a, T> {
-    type Item = Result<Token>;
+impl<'a, T: Iterator<Item = (&'a str, Span)>> Iterator for TokenIter<'a, T> {
+    type Item = Result<(Token, Span)>;
 
+    /// Errors always point at the token that couldn't be parsed
     fn next(&mut self) -> Option<Self::Item> {
-        let token = if let Some(token) = self.lexer.next() {
-            Self::parse_token(token)
-        } else {
-            None
-        };
-
-        token
+        let (token, span) = self.lexer.next()?;
+        Self::parse_token(token, span).map(|token| token.map(|token| (token, span)).at(span))
     }
 }
 
-impl<'a, T: Iterator<Item = &'a str>> TokenIter<'a, T> {
+impl<'a, T: Iterator<Item = (&'a str, Span)>> TokenIter<'a, T> {
     pub fn new(lexer: T) -> Self {
         Self { lexer }
     }
 
-    fn parse_token(token: &str) -> Option<Result<Token>> {
+    fn parse_token(token: &str, span: Span) -> Option<Result<Token>> {
         let token = if let Some(instruction) = Self::instruction(token) {
             Token::Mnemonic(instruction)
-        } else if let Some(string) = Self::string(token) {
-            return Some(string.map(Token::Ascii));
+        } else if let Some(literal) = Self::quoted(token, span) {
+            return Some(literal);
         } else if let Some(register) = Self::register(token) {
             Token::Register(register)
         } else if let Some(token) = Self::directive(token) {
@@ -285,6 +441,10 @@ impl<'a, T: Iterator<Item = &'a str>> TokenIter<'a, T> {
             token
         } else if token == "\n" {
             Token::Newline
+        } else if let Some(label) = Self::local_label(token) {
+            label
+        } else if let Some(float) = Self::float(token) {
+            return Some(float.map(Token::Float));
         } else if let Some(number) = Self::number(token) {
             // Early return here to avoid a big match statement
             return Some(number.map(Token::Number));
@@ -308,6 +468,20 @@ impl<'a, T: Iterator<Item = &'a str>> TokenIter<'a, T> {
             "/" => Some(Token::Div),
             "^" => Some(Token::Caret),
             "&" => Some(Token::Ampersand),
+            "|" => Some(Token::Pipe),
+            "%" => Some(Token::Percent),
+            "~" => Some(Token::Tilde),
+            "!" => Some(Token::Exclamation),
+            "<<" => Some(Token::ShiftLeft),
+            ">>" => Some(Token::ShiftRight),
+            "&&" => Some(Token::DoubleAmpersand),
+            "||" => Some(Token::DoublePipe),
+            "==" => Some(Token::DoubleEqual),
+            "!=" => Some(Token::NotEqual),
+            "<" => Some(Token::Less),
+            "<=" => Some(Token::LessEqual),
+            ">" => Some(Token::Greater),
+            ">=" => Some(Token::GreaterEqual),
             "@" => Some(Token::AtSign),
             ":" => Some(Token::Colon),
             "$" => Some(Token::Dollar),
@@ -318,15 +492,50 @@ impl<'a, T: Iterator<Item = &'a str>> TokenIter<'a, T> {
     fn directive(token: &str) -> Option<Directive> {
         match token {
             ".section" => Some(Directive::Section),
+            ".pushsection" => Some(Directive::Pushsection),
+            ".popsection" => Some(Directive::Popsection),
+            ".previous" => Some(Directive::Previous),
             ".equ" => Some(Directive::Equ),
+            ".set" => Some(Directive::Set),
             ".align" => Some(Directive::Align),
             ".skip" => Some(Directive::Skip),
             ".global" => Some(Directive::Global),
+            ".extern" => Some(Directive::Extern),
+            ".weak" => Some(Directive::Weak),
+            ".comm" => Some(Directive::Comm),
+            ".lcomm" => Some(Directive::Lcomm),
+            ".type" => Some(Directive::Type),
+            ".size" => Some(Directive::Size),
+            ".func" => Some(Directive::Func),
+            ".endfunc" => Some(Directive::Endfunc),
+            ".assert" => Some(Directive::Assert),
+            ".error" => Some(Directive::Error),
+            ".warning" => Some(Directive::Warning),
+            ".print" => Some(Directive::Print),
             ".u8" => Some(Directive::U8),
             ".u16" => Some(Directive::U16),
             ".u32" => Some(Directive::U32),
             ".u64" => Some(Directive::U64),
+            ".f32" => Some(Directive::F32),
+            ".f64" => Some(Directive::F64),
             ".ascii" => Some(Directive::Ascii),
+            ".macro" => Some(Directive::Macro),
+            ".endm" => Some(Directive::Endm),
+            ".if" => Some(Directive::If),
+            ".ifdef" => Some(Directive::Ifdef),
+            ".ifndef" => Some(Directive::Ifndef),
+            ".elif" => Some(Directive::Elif),
+            ".else" => Some(Directive::Else),
+            ".endif" => Some(Directive::Endif),
+            ".include" => Some(Directive::Include),
+            ".incbin" => Some(Directive::Incbin),
+            ".rept" => Some(Directive::Rept),
+            ".irp" => Some(Directive::Irp),
+            ".irpc" => Some(Directive::Irpc),
+            ".endr" => Some(Directive::Endr),
+            ".struct" => Some(Directive::Struct),
+            ".endstruct" => Some(Directive::Endstruct),
+            ".instance" => Some(Directive::Instance),
             _ => None,
         }
     }
@@ -532,24 +741,68 @@ impl<'a, T: Iterator<Item = &'a str>> TokenIter<'a, T> {
         }
     }
 
-    fn string(mut token: &str) -> Option<Result<Rc<str>>> {
-        if token.starts_with('"') {
-            if token.ends_with('"') {
-                token = token.trim_matches('"');
-            } else {
-                return Some(Err(anyhow!("Unterminated double quote string")));
+    /// Parses string and character literals. A single quoted token that decodes to exactly one
+    /// character is a character literal such as `'A'` or `'\n'` and becomes a number, anything else
+    /// is a string
+    fn quoted(token: &str, span: Span) -> Option<Result<Token>> {
+        let quote = token
+            .chars()
+            .next()
+            .filter(|ch| *ch == '"' || *ch == '\'')?;
+
+        let Some(contents) = Self::quoted_contents(token, quote) else {
+            let kind = if quote == '"' { "double" } else { "single" };
+            return Some(Err(anyhow!("Unterminated {kind} quote string")));
+        };
+
+        let decoded = match unescape(contents) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                // Offset by one to account for the opening quote
+                let span = span.subspan(token, e.start + 1, e.end + 1);
+                return Some(Err(error_at(span, e.message)));
             }
-        } else if token.starts_with('\'') {
-            if token.ends_with('\'') {
-                token = token.trim_matches('\'');
-            } else {
-                return Some(Err(anyhow!("Unterminated single quote string")));
+        };
+
+        let mut bytes = Vec::with_capacity(contents.len());
+        for ch in decoded.iter() {
+            match ch {
+                Unescaped::Byte(byte) => bytes.push(*byte),
+                Unescaped::Char(ch) => {
+                    bytes.extend_from_slice(ch.encode_utf8(&mut [0; 4]).as_bytes())
+                }
             }
-        } else {
-            return None;
         }
 
-        Some(Ok(Rc::from(token)))
+        if quote == '\'' && decoded.len() <= 1 {
+            let value = match decoded.first() {
+                None => return Some(Err(anyhow!("Empty character literal"))),
+                Some(Unescaped::Byte(byte)) => (*byte).into(),
+                Some(Unescaped::Char(ch)) => u32::from(*ch).into(),
+            };
+            return Some(Ok(Token::Character(value, Rc::from(bytes))));
+        }
+
+        Some(Ok(Token::Ascii(Rc::from(bytes))))
+    }
+
+    /// Returns the text between the quotes of a string or character literal, or None if the
+    /// closing quote is missing
+    fn quoted_contents(token: &str, quote: char) -> Option<&str> {
+        let contents = &token[quote.len_utf8()..];
+
+        let mut escaped = false;
+        for (i, ch) in contents.char_indices() {
+            if escaped {
+                escaped = false;
+            } else if ch == '\\' {
+                escaped = true;
+            } else if ch == quote {
+                return Some(&contents[..i]);
+            }
+        }
+
+        None
     }
 
     fn register(token: &str) -> Option<Register> {
@@ -587,27 +840,238 @@ impl<'a, T: Iterator<Item = &'a str>> TokenIter<'a, T> {
         }
     }
 
-    /// Tries to parse a number
+    /// Tries to parse a reference to a numeric local label, which is a decimal number followed
+    /// by `b` or `f`. This takes precedence over numbers, so `0b` is a label and not a binary
+    /// number without digits
+    fn local_label(token: &str) -> Option<Token> {
+        let direction = match token.chars().last()? {
+            'b' => Direction::Backward,
+            'f' => Direction::Forward,
+            _ => return None,
+        };
+
+        let digits = &token[..token.len() - 1];
+        if digits.is_empty() || !digits.chars().all(|ch| ch.is_ascii_digit()) {
+            return None;
+        }
+
+        Some(Token::LocalLabel(digits.parse().ok()?, direction))
+    }
+
+    /// Tries to parse a float literal like `1.5`, `2e10` or `1.5e-3`. Anything that starts with a
+    /// decimal digit and has a `.` or an exponent is a float
+    fn float(token: &str) -> Option<Result<f64>> {
+        if !token.starts_with(|ch: char| ch.is_ascii_digit())
+            || matches!(
+                token.get(..2),
+                Some("0x" | "0X" | "0b" | "0B" | "0o" | "0O")
+            )
+            || !token.contains(['.', 'e', 'E'])
+        {
+            return None;
+        }
+
+        if token.starts_with('_') || token.ends_with('_') || token.contains("__") {
+            return Some(Err(anyhow!(
+                "Float {token} has a digit separator that isn't between two digits"
+            )));
+        }
+
+        match token.replace('_', "").parse() {
+            Ok(num) => Some(Ok(num)),
+            Err(_) => Some(Err(anyhow!("Invalid float {token}"))),
+        }
+    }
+
+    /// Tries to parse a number.
+    ///
+    /// Numbers can be written in decimal, hexadecimal (`0x`), binary (`0b`) or octal (`0o`), and
+    /// may use `_` between digits as a separator
     fn number(token: &str) -> Option<Result<u64>> {
         if !token.starts_with(|ch: char| ch.is_ascii_digit()) {
-            None
-        } else if token.starts_with("0x") && token.len() >= 3 {
-            match u64::from_str_radix(&token[2..], 16) {
-                Ok(num) => Some(Ok(num)),
-                Err(e) => match e.kind() {
-                    IntErrorKind::PosOverflow => Some(Err(anyhow!("Number {token} is too large"))),
-                    IntErrorKind::NegOverflow => Some(Err(anyhow!("Number {token} is too small"))),
-                    IntErrorKind::InvalidDigit => {
-                        Some(Err(anyhow!("Number {token} contains an invalid digit")))
-                    }
-                    _ => Some(Err(anyhow!("Invalid number {token}"))),
-                },
-            }
-        } else {
-            match token.parse::<i64>().map(|value| value as u64) {
-                Ok(num) => Some(Ok(num)),
-                Err(_) => Some(Err(anyhow!("Invalid number {token}"))),
-            }
+            return None;
+        }
+
+        let (radix, digits) = match token.get(..2) {
+            Some("0x" | "0X") => (16, &token[2..]),
+            Some("0b" | "0B") => (2, &token[2..]),
+            Some("0o" | "0O") => (8, &token[2..]),
+            _ => (10, token),
+        };
+
+        if digits.is_empty() {
+            return Some(Err(anyhow!(
+                "Number {token} has no digits after its prefix"
+            )));
+        }
+
+        if digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+            return Some(Err(anyhow!(
+                "Number {token} has a digit separator that isn't between two digits"
+            )));
+        }
+
+        let digits = digits.replace('_', "");
+        match u64::from_str_radix(&digits, radix) {
+            Ok(num) => Some(Ok(num)),
+            Err(e) => match e.kind() {
+                IntErrorKind::PosOverflow => Some(Err(anyhow!("Number {token} is too large"))),
+                IntErrorKind::InvalidDigit => {
+                    let digit = digits
+                        .chars()
+                        .find(|ch| !ch.is_digit(radix))
+                        .unwrap_or_default();
+                    Some(Err(anyhow!(
+                        "Number {token} contains the invalid base {radix} digit '{digit}'"
+                    )))
+                }
+                _ => Some(Err(anyhow!("Invalid number {token}"))),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::error_span;
+    use crate::lexer::Lexer;
+
+    type Tokens<'a> = TokenIter<'a, Lexer<'a>>;
+
+    fn number(token: &str) -> u64 {
+        Tokens::number(token)
+            .expect("Token should be a number")
+            .expect("Number should be valid")
+    }
+
+    fn number_error(token: &str) -> String {
+        Tokens::number(token)
+            .expect("Token should be a number")
+            .expect_err("Number should be invalid")
+            .to_string()
+    }
+
+    #[test]
+    fn test_number() {
+        assert_eq!(number("1234"), 1234);
+        assert_eq!(number("18446744073709551615"), u64::MAX);
+        assert_eq!(number("0xff"), 0xff);
+        assert_eq!(number("0b1010_0000"), 0b1010_0000);
+        assert_eq!(number("0o755"), 0o755);
+        assert_eq!(number("1_000_000"), 1_000_000);
+        assert_eq!(number("0xdead_beef"), 0xdead_beef);
+
+        assert!(number_error("18446744073709551616").contains("too large"));
+        assert!(number_error("0b102").contains("invalid base 2 digit '2'"));
+        assert!(number_error("0o8").contains("invalid base 8 digit '8'"));
+        assert!(number_error("12a").contains("invalid base 10 digit 'a'"));
+        assert!(number_error("0x").contains("no digits"));
+        assert!(number_error("0x_ff").contains("digit separator"));
+        assert!(number_error("1__0").contains("digit separator"));
+        assert!(number_error("10_").contains("digit separator"));
+
+        assert!(Tokens::number("label").is_none());
+    }
+
+    fn float(token: &str) -> f64 {
+        Tokens::float(token)
+            .expect("Token should be a float")
+            .expect("Float should be valid")
+    }
+
+    #[test]
+    fn test_float() {
+        assert_eq!(float("1.5"), 1.5);
+        assert_eq!(float("2e10"), 2e10);
+        assert_eq!(float("1.5e-3"), 1.5e-3);
+        assert_eq!(float("1_000.25"), 1000.25);
+        assert_eq!(float("3."), 3.0);
+
+        assert!(
+            Tokens::float("1.2.3")
+                .unwrap()
+                .unwrap_err()
+                .to_string()
+                .contains("Invalid float 1.2.3")
+        );
+        assert!(Tokens::float("12").is_none());
+        assert!(Tokens::float("0x1e").is_none());
+        assert!(Tokens::float("inf").is_none());
+    }
+
+    #[test]
+    fn test_local_label() {
+        assert!(matches!(
+            Tokens::local_label("1b"),
+            Some(Token::LocalLabel(1, Direction::Backward))
+        ));
+        assert!(matches!(
+            Tokens::local_label("12f"),
+            Some(Token::LocalLabel(12, Direction::Forward))
+        ));
+        assert!(Tokens::local_label("b").is_none());
+        assert!(Tokens::local_label("0x1b").is_none());
+        assert!(Tokens::local_label("0b10").is_none());
+    }
+
+    fn quoted(token: &str) -> Result<Token> {
+        Tokens::quoted(token, Span::default()).expect("Token should be quoted")
+    }
+
+    fn character(token: &str) -> u64 {
+        match quoted(token).expect("Character literal should be valid") {
+            Token::Character(value, _) => value,
+            other => panic!("Expected a character literal but got {other:?}"),
         }
     }
+
+    fn string(token: &str) -> Rc<[u8]> {
+        match quoted(token).expect("String should be valid") {
+            Token::Ascii(string) => string,
+            other => panic!("Expected a string but got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_character() {
+        assert_eq!(character("'A'"), 0x41);
+        assert_eq!(character("'\\n'"), 0x0a);
+        assert_eq!(character("'\\x7f'"), 0x7f);
+        assert_eq!(character("'\\\\'"), b'\\'.into());
+        assert_eq!(character("'\\''"), b'\''.into());
+        assert_eq!(character("'\\u{e9}'"), 0xe9);
+
+        assert!(quoted("''").is_err());
+        assert!(quoted("'\\q'").is_err());
+        assert!(quoted("'\\x7'").is_err());
+        assert!(quoted("'\\x+f'").is_err());
+
+        // Anything longer than a single character is a string
+        assert_eq!(&*string("'string'"), b"string");
+        assert_eq!(&*string("\"A\""), b"A");
+    }
+
+    #[test]
+    fn test_string() {
+        assert_eq!(&*string("\"say \\\"hi\\\"\""), b"say \"hi\"");
+        assert_eq!(&*string("\"a\\r\\tb\""), b"a\r\tb");
+        assert_eq!(&*string("\"\\xff\\377\\u{e9}\""), b"\xff\xff\xc3\xa9");
+        assert_eq!(&*string("\"\""), b"");
+
+        assert!(quoted("\"unterminated").is_err());
+        assert!(quoted("\"escaped quote\\\"").is_err());
+    }
+
+    #[test]
+    fn test_escape_span() {
+        let source = ".ascii \"ok\", \"bad \\q\"";
+        let error = TokenIter::new(Lexer::new(source))
+            .find_map(|token| token.err())
+            .expect("The escape sequence should be invalid");
+        let span = error_span(&error).expect("The error should have a span");
+
+        assert_eq!(&source[span.start..span.end], "\\q");
+        assert_eq!((span.line, span.column), (1, 19));
+    }
 }